@@ -0,0 +1,39 @@
+#define_import_path frosty_grass::displacement
+
+// Mirrors `MAX_DISPLACEMENTS` in displacement.rs.
+const MAX_DISPLACEMENTS: u32 = 64u;
+
+// A `GrassDisplacer` or a point of its trail. Mirrors `DisplacementUniform` in
+// render/displacement.rs.
+struct Displacement {
+    position: vec3<f32>,
+    radius: f32,
+    strength: f32,
+};
+
+struct Displacements {
+    count: u32,
+    displacements: array<Displacement, MAX_DISPLACEMENTS>,
+};
+
+@group(3) @binding(6) var<uniform> displacements: Displacements;
+
+// How far the displacements bend the tip of a blade rooted at `root` away from them, per unit of
+// blade height, on the world XZ plane. Overlapping displacements, such as the points of a trail,
+// do not add up: the strongest one bends the blade.
+fn displacement_offset(root: vec3<f32>) -> vec2<f32> {
+	var offset = vec2<f32>(0.);
+	for (var i = 0u; i < displacements.count; i++) {
+		let displacement = displacements.displacements[i];
+		let dist = distance(root, displacement.position);
+		let away = root.xz - displacement.position.xz;
+		if dist >= displacement.radius || all(away == vec2<f32>(0.)) {
+			continue;
+		}
+		let push = normalize(away) * displacement.strength * (1. - dist / displacement.radius);
+		if dot(push, push) > dot(offset, offset) {
+			offset = push;
+		}
+	}
+	return offset;
+}
@@ -0,0 +1,26 @@
+#define_import_path frosty_grass::flatten
+
+// The bounds of a `GrassFlattenMap`. Mirrors `FlattenUniform` in render/flatten.rs.
+struct FlattenBounds {
+    min: vec2<f32>,
+    // Zero for grass without a flatten map.
+    size: vec2<f32>,
+};
+
+@group(3) @binding(7) var flatten_map: texture_2d<f32>;
+@group(3) @binding(8) var flatten_sampler: sampler;
+@group(3) @binding(9) var<uniform> flatten_bounds: FlattenBounds;
+
+// The flatness of the grass at `position` in the local space of its grassable, from `0` for
+// upright blades to `1` for blades squashed flat.
+fn flatness(position: vec3<f32>) -> f32 {
+	if any(flatten_bounds.size <= vec2<f32>(0.)) {
+		return 0.;
+	}
+	let uv = (position.xz - flatten_bounds.min) / flatten_bounds.size;
+	if any(uv < vec2<f32>(0.)) || any(uv > vec2<f32>(1.)) {
+		return 0.;
+	}
+	// The map has a single mip level, and vertex shaders cannot sample with implicit derivatives.
+	return textureSampleLevel(flatten_map, flatten_sampler, uv, 0.).r;
+}
@@ -1,18 +1,46 @@
-#import bevy_pbr::mesh_functions::{get_model_matrix, mesh_position_local_to_clip}
+#import bevy_pbr::mesh_view_bindings::view
+#import frosty_grass::instancing::{InstanceLod, InstanceTransform, lod_fade}
+#import frosty_grass::wind::wind_offset
+#import frosty_grass::displacement::displacement_offset
+#import frosty_grass::flatten::flatness
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_bindings
+#import bevy_pbr::prepass_io::{VertexOutput, FragmentOutput}
+#import bevy_render::globals::Globals
+
+// The prepass view bind group only holds the view, globals and previous view projection.
+@group(0) @binding(1) var<uniform> globals: Globals;
+#else
 #import bevy_pbr::mesh_view_bindings::globals
 #import bevy_pbr::forward_io::VertexOutput
 #import bevy_pbr::pbr_fragment::pbr_input_from_standard_material
 #import bevy_pbr::pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing}
 #import bevy_pbr::pbr_bindings::material;
+#endif
 
 struct Vertex {
     @location(0) position: vec3<f32>,
+#ifdef PREPASS_PIPELINE
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    @location(2) normal: vec3<f32>,
+#endif
+#else
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+#endif
 
-    @location(3) i_pos_scale: vec4<f32>,
+    @location(8) i_index: u32,
+    @location(9) i_pos_scale: vec4<f32>,
+    @location(10) i_color: vec4<f32>,
 };
 
+@group(3) @binding(0) var<uniform> instance_lod: InstanceLod;
+@group(3) @binding(1) var<uniform> instance_transform: InstanceTransform;
+
+// The height of blades squashed flat by a `GrassFlattenMap`, relative to upright blades.
+const FLATTENED_HEIGHT: f32 = 0.15;
+
 fn hash(in: f32) -> f32 {
 	let large = i32(in * 371311.);
 	return f32(large % 91033) / 91033.;
@@ -36,32 +64,93 @@ fn rotate_axis(point: vec3<f32>, axis: vec3<f32>, angle: f32) -> vec3<f32> {
 	return rotation_axis_matrix(axis, angle) * point;
 }
 
+// The world position of the root of a blade.
+fn blade_root(vertex: Vertex, world_from_local: mat4x4<f32>) -> vec3<f32> {
+	return (world_from_local * vec4<f32>(vertex.i_pos_scale.xyz, 1.)).xyz;
+}
+
+// The world position of a vertex of a blade placed by `world_from_local`, swaying in the
+// `GrassWind` at `time`, bent away from `GrassDisplacer`s and squashed by the `GrassFlattenMap`.
+fn blade_position(vertex: Vertex, world_from_local: mat4x4<f32>, time: f32) -> vec3<f32> {
+	let hashed = hash(vertex.i_pos_scale.x + vertex.i_pos_scale.z);
+	let root = blade_root(vertex, world_from_local);
+#ifdef INSTANCE_SHADOW_PASS
+	// The view is a light, which the level of detail was not picked for.
+	let scale = vertex.i_pos_scale.w;
+#else
+	let scale = vertex.i_pos_scale.w * lod_fade(instance_lod, vertex.i_index, distance(root, view.world_position));
+#endif
+	let squash = mix(1., FLATTENED_HEIGHT, flatness(vertex.i_pos_scale.xyz));
+	let blade = vertex.position * vec3<f32>(1., squash, 1.);
+    let local_position = rotate_axis(blade, vec3<f32>(0., 1., 0.), radians(360.) * hashed) * scale + vertex.i_pos_scale.xyz;
+    var position = (world_from_local * vec4<f32>(local_position, 1.)).xyz;
+    let lambda = clamp(position.y - root.y, 0., 1.);
+	let offset = wind_offset(position, lambda, hash(position.x + position.z), time) + displacement_offset(root) * lambda;
+	position.x += offset.x;
+	position.z += offset.y;
+	return position;
+}
+
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
-	let hashed = hash(vertex.i_pos_scale.x + vertex.i_pos_scale.z);
-    var position = rotate_axis(vertex.position, vec3<f32>(0., 1., 0.), radians(360.) * hashed) * vertex.i_pos_scale.w + vertex.i_pos_scale.xyz;
-    let lambda = clamp(position.y - vertex.i_pos_scale.y, 0., 1.);
-	position.x += sin(globals.time * 1. + position.x * 0.01 + position.z * 0.01) * 0.3 * lambda + sin(globals.time * hash(position.x + position.z)) * 0.3 * lambda;
+	let world_from_local = instance_transform.world_from_local;
+	let position = blade_position(vertex, world_from_local, globals.time);
+	let lambda = clamp(position.y - blade_root(vertex, world_from_local).y, 0., 1.);
 
     var out: VertexOutput;
-    out.position = mesh_position_local_to_clip(
-        get_model_matrix(0u),
-        vec4<f32>(position, 1.0)
-    );
+    out.position = view.view_proj * vec4<f32>(position, 1.);
 	out.world_position = vec4<f32>(position, 1.);
-	out.world_normal = vertex.normal;
-	let color = vec4<f32>(1., 1., 1., 1.);
+#ifdef PREPASS_PIPELINE
+#ifdef DEPTH_CLAMP_ORTHO
+	out.clip_position_unclamped = out.position;
+	out.position.z = min(out.position.z, 1.);
+#endif
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+	out.world_normal = normalize((world_from_local * vec4<f32>(vertex.normal, 0.)).xyz);
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+	out.previous_world_position = vec4<f32>(blade_position(vertex, instance_transform.previous_world_from_local, globals.time - globals.delta_time), 1.);
+#endif
+#else
+	out.world_normal = normalize((world_from_local * vec4<f32>(vertex.normal, 0.)).xyz);
+	let color = vertex.i_color;
 	out.color = mix(0.1 * color, 1.2 * color, lambda);
+#endif
     return out;
 }
 
+#ifdef PREPASS_PIPELINE
+#ifdef PREPASS_FRAGMENT
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+	var out: FragmentOutput;
+#ifdef NORMAL_PREPASS
+	out.normal = vec4<f32>(in.world_normal * 0.5 + vec3<f32>(0.5), 1.);
+#endif
+#ifdef DEPTH_CLAMP_ORTHO
+	out.frag_depth = in.clip_position_unclamped.z;
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+	let clip_position_t = view.unjittered_view_proj * in.world_position;
+	let clip_position = clip_position_t.xy / clip_position_t.w;
+	let previous_clip_position_t = prepass_bindings::previous_view_proj * in.previous_world_position;
+	let previous_clip_position = previous_clip_position_t.xy / previous_clip_position_t.w;
+	out.motion_vector = (clip_position - previous_clip_position) * vec2<f32>(0.5, -0.5);
+#endif
+	return out;
+}
+#endif
+#else
 @fragment
 fn fragment(
 	in: VertexOutput,
     @builtin(front_facing) is_front: bool,
 ) -> @location(0) vec4<f32> {
-	let pbr_input = pbr_input_from_standard_material(in, is_front);
+	var pbr_input = pbr_input_from_standard_material(in, is_front);
+	// The mesh uniform the flags are read from is not the one of this entity.
+	pbr_input.flags = instance_transform.flags;
     var color = apply_pbr_lighting(pbr_input);
     color = main_pass_post_lighting_processing(pbr_input, color);
 	return color;
 }
+#endif
@@ -0,0 +1,65 @@
+#import frosty_grass::instancing::{InstanceLod, lod_density, LOD_FADE}
+
+struct Culling {
+    world_from_local: mat4x4<f32>,
+    // The frustum's half spaces, without the far plane.
+    planes: array<vec4<f32>, 5>,
+    view_position: vec3<f32>,
+    instance_radius: f32,
+    lod: InstanceLod,
+    // Number of instances at the start of `instances` that are culled.
+    culled_count: u32,
+    // Size of an instance in `u32`s. Instances start with their position and scale.
+    instance_stride: u32,
+};
+
+struct DrawArgs {
+    vertex_or_index_count: u32,
+    instance_count: atomic<u32>,
+    rest: array<u32, 3>,
+};
+
+@group(0) @binding(0) var<uniform> culling: Culling;
+@group(0) @binding(1) var<storage, read> instances: array<u32>;
+@group(0) @binding(2) var<storage, read_write> culled_instances: array<u32>;
+@group(0) @binding(3) var<storage, read_write> culled_indices: array<u32>;
+@group(0) @binding(4) var<storage, read_write> draw_args: DrawArgs;
+
+// Culls one instance, mirroring `cull_instances` in culling.rs.
+@compute @workgroup_size(64)
+fn cull(@builtin(global_invocation_id) id: vec3<u32>) {
+	let index = id.x;
+	if index >= culling.culled_count {
+		return;
+	}
+	let start = index * culling.instance_stride;
+	let position_scale = bitcast<vec4<f32>>(vec4<u32>(
+		instances[start],
+		instances[start + 1u],
+		instances[start + 2u],
+		instances[start + 3u],
+	));
+
+	let center = (culling.world_from_local * vec4<f32>(position_scale.xyz, 1.)).xyz;
+	let max_scale = max(
+		length(culling.world_from_local[0].xyz),
+		max(length(culling.world_from_local[1].xyz), length(culling.world_from_local[2].xyz)),
+	);
+	let radius = culling.instance_radius * abs(position_scale.w) * max_scale;
+	for (var i = 0u; i < 5u; i++) {
+		if dot(culling.planes[i], vec4<f32>(center, 1.)) + radius <= 0. {
+			return;
+		}
+	}
+	let keep_fraction = min(lod_density(culling.lod, distance(center, culling.view_position)) * (1. + LOD_FADE), 1.);
+	if f32(index) / f32(culling.lod.instance_count) >= keep_fraction {
+		return;
+	}
+
+	let culled = atomicAdd(&draw_args.instance_count, 1u);
+	let culled_start = culled * culling.instance_stride;
+	for (var word = 0u; word < culling.instance_stride; word++) {
+		culled_instances[culled_start + word] = instances[start + word];
+	}
+	culled_indices[culled] = index;
+}
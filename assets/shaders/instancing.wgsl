@@ -0,0 +1,42 @@
+#define_import_path frosty_grass::instancing
+
+struct InstanceLod {
+    distances: vec4<f32>,
+    densities: vec4<f32>,
+    levels: u32,
+    instance_count: u32,
+};
+
+// The transform of an instance entity, which its instances are placed in.
+struct InstanceTransform {
+    world_from_local: mat4x4<f32>,
+    previous_world_from_local: mat4x4<f32>,
+    // The `MeshFlags` of the entity.
+    flags: u32,
+};
+
+// Fraction of the instances over which thinned out instances fade. Mirrors `LOD_FADE` in
+// instancing.rs.
+const LOD_FADE: f32 = 0.05;
+
+// The density of instances `distance` away from the camera.
+fn lod_density(lod: InstanceLod, distance: f32) -> f32 {
+	var density = 1.;
+	var start = 0.;
+	for (var i = 0u; i < lod.levels; i++) {
+		let end = lod.distances[i];
+		if distance < end {
+			return mix(density, lod.densities[i], (distance - start) / (end - start));
+		}
+		density = lod.densities[i];
+		start = end;
+	}
+	return density;
+}
+
+// Shrinks instances away as the level of detail thins them out, from `1` for instances that are
+// fully drawn down to `0`. Instances are thinned out from the end of the instance data first.
+fn lod_fade(lod: InstanceLod, index: u32, distance: f32) -> f32 {
+	let rank = f32(index) / f32(lod.instance_count);
+	return clamp((lod_density(lod, distance) * (1. + LOD_FADE) - rank) / LOD_FADE, 0., 1.);
+}
@@ -0,0 +1,85 @@
+#define_import_path frosty_grass::wind
+
+// The `GrassWind` resource. Mirrors `GrassWindUniform` in render/wind.rs.
+struct GrassWind {
+    // Normalized, or zero when there is no wind.
+    direction: vec2<f32>,
+    strength: f32,
+    gust_frequency: f32,
+    turbulence_scale: f32,
+    // The world size of one tile of the noise texture.
+    noise_scale: f32,
+    scroll_speed: f32,
+};
+
+@group(3) @binding(2) var<uniform> wind: GrassWind;
+// Scales the gusts. White without a `WindNoise`.
+@group(3) @binding(3) var wind_noise: texture_2d<f32>;
+@group(3) @binding(4) var wind_noise_sampler: sampler;
+
+// Mirrors `MAX_WIND_ZONES` in wind.rs.
+const MAX_WIND_ZONES: u32 = 16u;
+
+const WIND_ZONE_RADIAL: u32 = 0u;
+const WIND_ZONE_DIRECTIONAL: u32 = 1u;
+const WIND_ZONE_VORTEX: u32 = 2u;
+
+// A `GrassWindZone`. Mirrors `WindZoneUniform` in render/wind.rs.
+struct WindZone {
+    position: vec3<f32>,
+    kind: u32,
+    // The forward direction of directional zones, on the XZ plane.
+    direction: vec2<f32>,
+    radius: f32,
+    strength: f32,
+    falloff: f32,
+};
+
+struct WindZones {
+    count: u32,
+    zones: array<WindZone, MAX_WIND_ZONES>,
+};
+
+@group(3) @binding(5) var<uniform> wind_zones: WindZones;
+
+// The noise under `position` at `time`, scrolling along the wind.
+fn wind_gust_noise(position: vec3<f32>, time: f32) -> f32 {
+	let uv = (position.xz - wind.direction * wind.scroll_speed * time) / wind.noise_scale;
+	// Vertex shaders pick the mip level themselves.
+	return textureSampleLevel(wind_noise, wind_noise_sampler, uv, 0.).r;
+}
+
+// How far `zone` pushes the tip of a blade at `position`, per unit of blade height. Mirrors
+// `GrassWindZone::push` in wind.rs.
+fn wind_zone_offset(zone: WindZone, position: vec3<f32>) -> vec2<f32> {
+	let dist = distance(position, zone.position);
+	if dist >= zone.radius {
+		return vec2<f32>(0.);
+	}
+	let offset = position.xz - zone.position.xz;
+	var away = vec2<f32>(0.);
+	if any(offset != vec2<f32>(0.)) {
+		away = normalize(offset);
+	}
+	var direction = away;
+	if zone.kind == WIND_ZONE_DIRECTIONAL {
+		direction = zone.direction;
+	} else if zone.kind == WIND_ZONE_VORTEX {
+		direction = vec2<f32>(away.y, -away.x);
+	}
+	return direction * zone.strength * pow(1. - dist / zone.radius, zone.falloff);
+}
+
+// How far the wind pushes a point of a blade at `position` sideways at `time`, on the world XZ
+// plane. `lambda` is the height of the point above the root of its blade, clamped to `[0, 1]`,
+// and `flutter` is a random number of the blade that sets how it flutters on its own.
+fn wind_offset(position: vec3<f32>, lambda: f32, flutter: f32, time: f32) -> vec2<f32> {
+	let wave = sin(radians(360.) * wind.gust_frequency * time + dot(position.xz, wind.direction) / wind.turbulence_scale);
+	let gust = wave * wind_gust_noise(position, time);
+	let sway = (gust + sin(time * flutter)) * wind.strength;
+	var offset = wind.direction * sway;
+	for (var i = 0u; i < wind_zones.count; i++) {
+		offset += wind_zone_offset(wind_zones.zones[i], position);
+	}
+	return offset * lambda;
+}
@@ -12,9 +12,6 @@ use frosty_grass::grass::{GrassPlugin, Grassable};
 #[derive(Component)]
 pub struct Terrain;
 
-#[derive(Component)]
-struct GrassPoints(Vec<Vec3>);
-
 pub struct TerrainPlugin;
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
@@ -30,7 +27,7 @@ fn setup_terrain(
     asset_server: Res<AssetServer>,
 ) {
     let terrain_mesh = _create_mesh(128, 128, 1., 1);
-    let terrain_mesh_handle = meshes.add(terrain_mesh.clone().into());
+    let terrain_mesh_handle = meshes.add(terrain_mesh.clone());
 
     let grass_mesh_handle = asset_server.load::<Mesh>("models/grass.gltf#Mesh0/Primitive0");
     let grass_material = StandardMaterial {
@@ -58,12 +55,13 @@ fn setup_terrain(
             density: 32.,
             grass_mesh: grass_mesh_handle,
             grass_material: grass_material_handle,
+            ..default()
         },
     ));
 }
 
 fn _terrain_height(fbm: &Fbm<Perlin>, x: f32, y: f32) -> f32 {
-    return fbm.get([x as f64 * 0.05678, y as f64 * 0.05678]) as f32;
+    fbm.get([x as f64 * 0.05678, y as f64 * 0.05678]) as f32
 }
 
 #[rustfmt::skip]
@@ -84,7 +82,7 @@ fn _create_mesh(width: usize, height: usize, scale: f32, tex_scale: usize) -> Me
         .set_is_seamless(true)
         .build();
 
-    let mut vertices = Vec::with_capacity((width + 1) * (height * 1));
+    let mut vertices = Vec::with_capacity((width + 1) * (height + 1));
     for i in 0..(width+1) {
         let x_pos = i as f32 * scale;
         for j in 0..(height+1) {
@@ -121,7 +119,7 @@ fn _create_mesh(width: usize, height: usize, scale: f32, tex_scale: usize) -> Me
             uv_0s.push([u_idx + tex_scale_inv, v_idx]);
             uv_0s.push([u_idx + tex_scale_inv, v_idx + tex_scale_inv]);
 
-            indices.push(ind_offset + 0);
+            indices.push(ind_offset);
             indices.push(ind_offset + 3);
             indices.push(ind_offset + 1);
             indices.push(ind_offset + 1);
@@ -147,7 +145,7 @@ fn _create_mesh(width: usize, height: usize, scale: f32, tex_scale: usize) -> Me
     .with_indices(Some(Indices::U32(indices)))
 }
 
-fn _average_normal(vertices: &Vec<Vec3>, width: usize, height: usize, x: usize, y: usize) -> Vec3 {
+fn _average_normal(vertices: &[Vec3], width: usize, height: usize, x: usize, y: usize) -> Vec3 {
     if let Some(vertex) = vertices.get(x * height + y) {
         let mut normal = Vec3::ZERO;
         let left = if x > 0 {
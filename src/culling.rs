@@ -0,0 +1,65 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        primitives::{Frustum, Sphere},
+    },
+};
+
+/// How finely instanced entities are culled. Insert it as a resource to change it.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstanceCulling {
+    /// Entities are frustum culled as a whole, and draw as many of their instances as their level
+    /// of detail asks for.
+    #[default]
+    Entity,
+    /// Every instance is also frustum culled, and thinned out by its own distance to the camera,
+    /// in a compute pass. Where compute shaders are not supported, the instances are culled on
+    /// the CPU with [`cull_instances`] instead.
+    Instance,
+}
+
+/// A view that instances are culled against.
+#[derive(Clone, Debug)]
+pub struct CullingView {
+    pub frustum: Frustum,
+    pub position: Vec3,
+}
+
+/// Returns the indices of the `instances` that are visible from `view`, in order. Mirrors the
+/// `cull` compute shader in `instance_culling.wgsl`.
+///
+/// Each instance is given by its local position and its scale. At a scale of `1`, its mesh fits
+/// in a sphere of `radius` around its position. `keep_fraction` gives the fraction of instances
+/// that are kept at a distance from the camera, which are thinned out from the end of
+/// `instances` first. The far plane of the frustum is ignored, like Bevy's frustum culling does.
+pub fn cull_instances(
+    view: &CullingView,
+    world_from_local: &GlobalTransform,
+    radius: f32,
+    instances: &[Vec4],
+    keep_fraction: impl Fn(f32) -> f32,
+) -> Vec<u32> {
+    let matrix = world_from_local.affine().matrix3;
+    let max_scale = matrix
+        .x_axis
+        .length()
+        .max(matrix.y_axis.length())
+        .max(matrix.z_axis.length());
+    let count = instances.len() as f32;
+
+    instances
+        .iter()
+        .enumerate()
+        .filter(|(index, instance)| {
+            let center = world_from_local.transform_point(instance.truncate());
+            let sphere = Sphere {
+                center: center.into(),
+                radius: radius * instance.w.abs() * max_scale,
+            };
+            (*index as f32 / count) < keep_fraction(view.position.distance(center))
+                && view.frustum.intersects_sphere(&sphere, false)
+        })
+        .map(|(index, _)| index as u32)
+        .collect()
+}
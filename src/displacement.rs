@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    render::{ExtractSchedule, Render, RenderApp, RenderSet},
+    transform::TransformSystem,
+    utils::HashMap,
+};
+
+use crate::render::displacement::{
+    extract_displacements, prepare_displacements, ExtractedDisplacements,
+};
+
+/// The most displacements that bend grass at once, counting both [`GrassDisplacer`]s and the
+/// trails they leave behind. Mirrors `MAX_DISPLACEMENTS` in `displacement.wgsl`.
+pub const MAX_DISPLACEMENTS: usize = 64;
+
+/// Bends grass blades away from the entity, such as a player or NPC walking through them.
+///
+/// Blades whose roots are within `radius` of the entity are bent away from it by up to
+/// `strength` per unit of blade height, fading out towards the edge of the radius. Overlapping
+/// displacers do not add up, the strongest one bends the blade. The bounds of grass chunks grow
+/// to fit the strongest displacer like they do for the strength of the
+/// [`GrassWind`](crate::wind::GrassWind), without sampling the grass again.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct GrassDisplacer {
+    pub radius: f32,
+    pub strength: f32,
+    /// How many seconds blades take to spring back once the entity has left them, or `None` to
+    /// spring back at once. Until then, the displacer leaves a trail behind it.
+    pub recovery_time: Option<f32>,
+    /// How much flatness the entity paints into the
+    /// [`GrassFlattenMap`](crate::flatten::GrassFlattenMap)s under it per second, leaving lasting
+    /// tracks. `0` leaves no tracks.
+    pub flatten_rate: f32,
+}
+
+impl Default for GrassDisplacer {
+    fn default() -> Self {
+        Self {
+            radius: 1.,
+            strength: 1.,
+            recovery_time: None,
+            flatten_rate: 0.,
+        }
+    }
+}
+
+/// How far the strongest of `displacers` can bend the tip of a blade sideways, per unit of
+/// blade height. Their trails are never stronger than they were.
+pub fn max_displacement_sway<'a>(displacers: impl IntoIterator<Item = &'a GrassDisplacer>) -> f32 {
+    displacers
+        .into_iter()
+        .map(|displacer| displacer.strength.abs())
+        .fold(0., f32::max)
+}
+
+/// A point of the trail of a [`GrassDisplacer`], where it last pressed the grass.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TrailPoint {
+    pub position: Vec3,
+    /// The elapsed time the displacer was last within half its radius of the point.
+    pub pressed_at: f32,
+}
+
+/// The trail a [`GrassDisplacer`] with a recovery time leaves behind it, newest point last.
+#[derive(Clone, Debug)]
+pub(crate) struct DisplacerTrail {
+    pub points: VecDeque<TrailPoint>,
+    pub radius: f32,
+    pub strength: f32,
+    pub recovery_time: f32,
+}
+
+impl DisplacerTrail {
+    /// The strength of `point` at `time`, fading linearly to `0` over the recovery time.
+    pub fn strength_at(&self, point: &TrailPoint, time: f32) -> f32 {
+        self.strength * (1. - (time - point.pressed_at) / self.recovery_time).max(0.)
+    }
+}
+
+/// The trails of all displacers. Trails outlive their displacers until they have recovered.
+#[derive(Resource, Default)]
+pub(crate) struct DisplacerTrails(pub HashMap<Entity, DisplacerTrail>);
+
+/// Extracts [`GrassDisplacer`]s to the render world for the grass shaders.
+pub struct GrassDisplacerPlugin;
+
+impl Plugin for GrassDisplacerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DisplacerTrails>().add_systems(
+            PostUpdate,
+            update_trails.after(TransformSystem::TransformPropagate),
+        );
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedDisplacements>()
+            .add_systems(ExtractSchedule, extract_displacements)
+            .add_systems(
+                Render,
+                prepare_displacements.in_set(RenderSet::PrepareResources),
+            );
+    }
+}
+
+/// Records where displacers with a recovery time pressed the grass, and forgets trail points
+/// that have recovered.
+fn update_trails(
+    time: Res<Time>,
+    mut trails: ResMut<DisplacerTrails>,
+    displacers_q: Query<(Entity, &GrassDisplacer, &GlobalTransform)>,
+) {
+    let now = time.elapsed_seconds();
+    for (entity, displacer, transform) in &displacers_q {
+        let Some(recovery_time) = displacer.recovery_time else {
+            trails.0.remove(&entity);
+            continue;
+        };
+        let trail = trails.0.entry(entity).or_insert_with(|| DisplacerTrail {
+            points: VecDeque::new(),
+            radius: displacer.radius,
+            strength: displacer.strength,
+            recovery_time,
+        });
+        trail.radius = displacer.radius;
+        trail.strength = displacer.strength;
+        trail.recovery_time = recovery_time;
+
+        let position = transform.translation();
+        match trail.points.back_mut() {
+            // Points are spaced half a radius apart, so that the trail bends grass evenly.
+            Some(point) if point.position.distance(position) < displacer.radius * 0.5 => {
+                point.pressed_at = now;
+            }
+            _ => trail.points.push_back(TrailPoint {
+                position,
+                pressed_at: now,
+            }),
+        }
+    }
+    trails.0.retain(|_, trail| {
+        while trail
+            .points
+            .front()
+            .is_some_and(|point| now - point.pressed_at >= trail.recovery_time)
+        {
+            trail.points.pop_front();
+        }
+        !trail.points.is_empty()
+    });
+}
@@ -0,0 +1,163 @@
+use bevy::{
+    prelude::*,
+    render::{ExtractSchedule, Render, RenderApp, RenderSet},
+    transform::TransformSystem,
+};
+
+use crate::displacement::GrassDisplacer;
+use crate::render::flatten::{
+    extract_flatten_maps, prepare_flatten_textures, ExtractedFlattenMaps,
+};
+
+/// How flattened the grass of a [`Grassable`](crate::grass::Grassable) is, such as by the tracks
+/// of vehicles. Insert it next to the `Grassable`.
+///
+/// The map covers the XZ bounds of the grassable's mesh in its local space, once the mesh is
+/// loaded. Each texel holds a flatness between `0`, for upright blades, and `1`, for blades
+/// squashed flat. [`GrassDisplacer`]s with a `flatten_rate` paint into the map, and so does
+/// [`GrassFlattenMap::paint`], and every texel recovers at the `decay_rate`.
+#[derive(Component, Clone, Debug)]
+pub struct GrassFlattenMap {
+    /// How much flatness every texel loses per second.
+    pub decay_rate: f32,
+    resolution: UVec2,
+    bounds: Option<Rect>,
+    /// The flatness of every texel, in rows from the minimum corner of the bounds.
+    values: Vec<f32>,
+}
+
+impl GrassFlattenMap {
+    /// A map of `resolution` texels along the local X and Z axes, with no flattened grass.
+    ///
+    /// Panics if either axis has no texels.
+    pub fn new(resolution: UVec2, decay_rate: f32) -> Self {
+        assert!(
+            resolution.cmpge(UVec2::ONE).all(),
+            "grass flatten maps need at least one texel along each axis, but got {resolution}"
+        );
+        Self {
+            decay_rate,
+            resolution,
+            bounds: None,
+            values: vec![0.; (resolution.x * resolution.y) as usize],
+        }
+    }
+
+    pub fn resolution(&self) -> UVec2 {
+        self.resolution
+    }
+
+    /// The local XZ bounds the map covers, or `None` until they are known.
+    pub fn bounds(&self) -> Option<Rect> {
+        self.bounds
+    }
+
+    /// Covers `bounds` from now on, clearing the map if they changed.
+    pub fn set_bounds(&mut self, bounds: Rect) {
+        if self.bounds != Some(bounds) {
+            self.bounds = Some(bounds);
+            self.values.iter_mut().for_each(|value| *value = 0.);
+        }
+    }
+
+    /// The flatness of every texel, in rows from the minimum corner of the bounds.
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// The flatness of the texel under the local XZ `position`, or `0` outside the bounds.
+    pub fn flatness(&self, position: Vec2) -> f32 {
+        let Some(bounds) = self.bounds.filter(|bounds| bounds.contains(position)) else {
+            return 0.;
+        };
+        let texel = ((position - bounds.min) / bounds.size() * self.resolution.as_vec2())
+            .as_uvec2()
+            .min(self.resolution - 1);
+        self.values[(texel.y * self.resolution.x + texel.x) as usize]
+    }
+
+    /// Flattens the grass within `radius` of the local XZ `position` by up to `amount`, fading out
+    /// linearly towards the edge of the radius. Does nothing until the bounds are known.
+    pub fn paint(&mut self, position: Vec2, radius: f32, amount: f32) {
+        let Some(bounds) = self.bounds else {
+            return;
+        };
+        let texel_size = bounds.size() / self.resolution.as_vec2();
+        let to_texel = |point: Vec2| ((point - bounds.min) / texel_size).floor().as_ivec2();
+        let max = self.resolution.as_ivec2() - 1;
+        let first = to_texel(position - radius).max(IVec2::ZERO);
+        let last = to_texel(position + radius).min(max);
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let center = bounds.min + (Vec2::new(x as f32, y as f32) + 0.5) * texel_size;
+                let distance = center.distance(position);
+                if distance < radius {
+                    let value = &mut self.values[(y * self.resolution.x as i32 + x) as usize];
+                    *value = (*value + amount * (1. - distance / radius)).min(1.);
+                }
+            }
+        }
+    }
+
+    /// Lets every texel recover by `amount`.
+    pub fn decay(&mut self, amount: f32) {
+        self.values
+            .iter_mut()
+            .for_each(|value| *value = (*value - amount).max(0.));
+    }
+}
+
+/// Paints the tracks of [`GrassDisplacer`]s into [`GrassFlattenMap`]s, uploads the maps to the
+/// GPU and lets them recover.
+pub struct GrassFlattenPlugin;
+
+impl Plugin for GrassFlattenPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            update_flatten_maps.after(TransformSystem::TransformPropagate),
+        );
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedFlattenMaps>()
+            .add_systems(ExtractSchedule, extract_flatten_maps)
+            .add_systems(
+                Render,
+                prepare_flatten_textures.in_set(RenderSet::PrepareResources),
+            );
+    }
+}
+
+/// Paints the displacers into the flatten maps and lets the maps recover. Maps are only marked
+/// as changed, and uploaded again, when their values change.
+fn update_flatten_maps(
+    time: Res<Time>,
+    mut maps_q: Query<(&mut GrassFlattenMap, &GlobalTransform)>,
+    displacers_q: Query<(&GrassDisplacer, &GlobalTransform)>,
+) {
+    let delta = time.delta_seconds();
+    for (mut map, transform) in &mut maps_q {
+        let Some(bounds) = map.bounds() else {
+            continue;
+        };
+        let local_from_world = transform.affine().inverse();
+        for (displacer, displacer_transform) in &displacers_q {
+            if displacer.flatten_rate <= 0. {
+                continue;
+            }
+            let position = local_from_world
+                .transform_point3(displacer_transform.translation())
+                .xz();
+            let radius = local_from_world
+                .transform_vector3(Vec3::X * displacer.radius)
+                .length();
+            let reach = Rect::from_corners(bounds.min - radius, bounds.max + radius);
+            if reach.contains(position) {
+                map.paint(position, radius, displacer.flatten_rate * delta);
+            }
+        }
+        if map.decay_rate > 0. && map.values().iter().any(|value| *value > 0.) {
+            let decay = map.decay_rate * delta;
+            map.decay(decay);
+        }
+    }
+}
@@ -1,82 +1,847 @@
-use bevy::{prelude::*, render::view::NoFrustumCulling};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::{
+        primitives::Aabb,
+        render_resource::{
+            AsBindGroup, BindGroupLayoutEntry, BindingType, BufferBindingType, SamplerBindingType,
+            ShaderStages, TextureSampleType, TextureViewDimension, VertexAttribute, VertexFormat,
+        },
+        ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+    transform::TransformSystem,
+    utils::{HashMap, HashSet},
+};
 use bytemuck::{Pod, Zeroable};
+use rand::prelude::*;
+
+use crate::sampling::{
+    DensityMap, MeshSampler, PoissonDiskSampler, SlopeLimit, UniformRandomSampler, UpDirection,
+    VertexMask,
+};
+use crate::texture::{TextureChannel, TextureMap};
+
+use crate::displacement::{max_displacement_sway, GrassDisplacer, GrassDisplacerPlugin};
+use crate::flatten::{GrassFlattenMap, GrassFlattenPlugin};
+use crate::render::displacement::GrassDisplacementBuffer;
+use crate::render::flatten::{prepare_flatten_textures, FlattenTextures};
+use crate::render::grass::{
+    extract_grass_chunks, prepare_grass_bindings, prepare_grass_flatten_bindings,
+    ExtractedGrassChunks,
+};
+use crate::render::instancing::{
+    packed_vertex_attributes, InstanceData, InstanceLod, InstancedMaterial, InstancingPlugin,
+    FIRST_INSTANCE_LOCATION, SHARED_INSTANCE_BINDING,
+};
+use crate::render::wind::{prepare_grass_wind, GrassWindBindings};
+use crate::wind::{max_zone_sway, GrassWind, GrassWindPlugin, GrassWindZone};
 
-use crate::sampling::{MeshSampler, UniformRandomSampler};
+/// The shader that draws grass with a [`StandardMaterial`].
+pub const GRASS_SHADER_PATH: &str = "shaders/grass.wgsl";
 
-use crate::render::instancing::{InstanceData, InstancedMaterial, InstancingPlugin};
+/// The first binding of group 3 of the flatten map, after the wind and displacement bindings
+/// shared by all grass.
+const FLATTEN_BINDING: u32 = SHARED_INSTANCE_BINDING + 5;
 
-#[derive(Component, Copy, Clone, Pod, Zeroable)]
+/// A blade of grass, drawn with the material `M`.
+#[derive(Component)]
 #[repr(C)]
-pub struct Grass {
+pub struct Grass<M = StandardMaterial> {
     position: Vec3,
     scale: f32,
+    /// Linear RGBA tint multiplied into the blade's color.
+    color: Vec4,
+    marker: PhantomData<M>,
+}
+
+impl<M> Clone for Grass<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
 
-impl InstancedMaterial for Grass {
-    type M = StandardMaterial;
+impl<M> Copy for Grass<M> {}
+
+// SAFETY: `Grass` is `repr(C)`, made up of `Pod` fields without padding between them, and a
+// zero sized marker.
+unsafe impl<M: 'static> Zeroable for Grass<M> {}
+unsafe impl<M: 'static> Pod for Grass<M> {}
+
+impl<M: GrassMaterial> InstancedMaterial for Grass<M> {
+    type M = M;
 
     fn shader_path() -> &'static str {
-        "shaders/grass.wgsl"
+        M::shader_path()
+    }
+
+    fn vertex_attributes() -> Vec<VertexAttribute> {
+        // The position and scale, then the color.
+        packed_vertex_attributes(
+            FIRST_INSTANCE_LOCATION,
+            &[VertexFormat::Float32x4, VertexFormat::Float32x4],
+        )
+    }
+
+    fn prepass_shader_path() -> Option<&'static str> {
+        Some(M::shader_path())
+    }
+
+    fn entity_layout_entries() -> Vec<BindGroupLayoutEntry> {
+        // The `GrassFlattenMap` of the grassable, its sampler and its bounds.
+        let entry = |binding, ty| BindGroupLayoutEntry {
+            binding: FLATTEN_BINDING + binding,
+            visibility: ShaderStages::VERTEX,
+            ty,
+            count: None,
+        };
+        vec![
+            entry(
+                0,
+                BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+            ),
+            entry(1, BindingType::Sampler(SamplerBindingType::Filtering)),
+            entry(
+                2,
+                BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+            ),
+        ]
+    }
+
+    fn shared_layout_entries() -> Vec<BindGroupLayoutEntry> {
+        // The `GrassWind`, its noise texture and sampler, the wind zones, then the displacements.
+        let entry = |binding, ty| BindGroupLayoutEntry {
+            binding: SHARED_INSTANCE_BINDING + binding,
+            visibility: ShaderStages::VERTEX,
+            ty,
+            count: None,
+        };
+        let uniform = BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        };
+        vec![
+            entry(0, uniform),
+            entry(
+                1,
+                BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+            ),
+            entry(2, BindingType::Sampler(SamplerBindingType::Filtering)),
+            entry(3, uniform),
+            entry(4, uniform),
+        ]
     }
 }
 
+/// A material that grass can be drawn with, at group 1 of its shader.
+///
+/// Grass is drawn with a [`StandardMaterial`] by default. To give the grass shader uniforms of
+/// its own, such as wind strength or tip and base colors, extend the standard material with a
+/// [`GrassExtension`]. Any other material can implement this trait directly, with a shader that
+/// places and sways blades the way `grass.wgsl` does. Grass drawn with a material other than the
+/// standard material is added with a [`GrassMaterialPlugin`].
+pub trait GrassMaterial: Material {
+    /// The shader that draws the grass, both in the main pass and into the prepasses and shadow
+    /// maps.
+    fn shader_path() -> &'static str;
+}
+
+impl GrassMaterial for StandardMaterial {
+    fn shader_path() -> &'static str {
+        GRASS_SHADER_PATH
+    }
+}
+
+/// Extends the [`StandardMaterial`] grass is drawn with by bindings of its own, from binding
+/// `100` of group 1 onwards.
+///
+/// ```
+/// # use bevy::pbr::{ExtendedMaterial, MaterialExtension};
+/// # use bevy::{prelude::*, render::render_resource::AsBindGroup};
+/// # use frosty_grass::grass::{GrassExtension, GrassMaterialPlugin, Grassable};
+/// #[derive(Asset, AsBindGroup, TypePath, Clone)]
+/// struct GrassColors {
+///     #[uniform(100)]
+///     tip: Color,
+///     #[uniform(101)]
+///     base: Color,
+///     #[uniform(102)]
+///     wind_strength: f32,
+/// }
+///
+/// impl MaterialExtension for GrassColors {}
+///
+/// impl GrassExtension for GrassColors {
+///     // A copy of `grass.wgsl` that declares
+///     // `@group(1) @binding(100) var<uniform> tip: vec4<f32>;` and so on.
+///     fn shader_path() -> &'static str {
+///         "shaders/colored_grass.wgsl"
+///     }
+/// }
+///
+/// type ColoredGrass = ExtendedMaterial<StandardMaterial, GrassColors>;
+///
+/// fn build(app: &mut App) {
+///     app.add_plugins(GrassMaterialPlugin::<ColoredGrass>::default());
+/// }
+///
+/// fn spawn(
+///     mut commands: Commands,
+///     mut materials: ResMut<Assets<ColoredGrass>>,
+///     terrain: Handle<Mesh>,
+///     blade: Handle<Mesh>,
+/// ) {
+///     commands.spawn((
+///         SpatialBundle::default(),
+///         Grassable::<ColoredGrass> {
+///             mesh: terrain,
+///             grass_mesh: blade,
+///             grass_material: materials.add(ExtendedMaterial {
+///                 base: StandardMaterial::default(),
+///                 extension: GrassColors {
+///                     tip: Color::YELLOW_GREEN,
+///                     base: Color::DARK_GREEN,
+///                     wind_strength: 0.3,
+///                 },
+///             }),
+///             ..default()
+///         },
+///     ));
+/// }
+/// ```
+pub trait GrassExtension: MaterialExtension {
+    /// The shader that draws the grass, see [`GrassMaterial::shader_path`].
+    fn shader_path() -> &'static str;
+}
+
+impl<E: GrassExtension> GrassMaterial for ExtendedMaterial<StandardMaterial, E> {
+    fn shader_path() -> &'static str {
+        E::shader_path()
+    }
+}
+
+/// The distribution used to place grass blades on a [`Grassable`] mesh.
+#[derive(Clone, Default)]
+pub enum GrassSampler {
+    /// Blades are placed independently at random, see [`UniformRandomSampler`].
+    #[default]
+    Uniform,
+    /// Blades are kept at least `min_distance` apart, see [`PoissonDiskSampler`].
+    PoissonDisk { min_distance: f32 },
+    /// Blades are placed by a user provided [`MeshSampler`]. The sampler is used as is, so the
+    /// `density`, `seed`, `density_map`, `vertex_mask` and `slope` of the [`Grassable`] are not
+    /// applied to it.
+    Custom(Arc<dyn MeshSampler + Send + Sync>),
+}
+
+impl GrassSampler {
+    pub fn custom(sampler: impl MeshSampler + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(sampler))
+    }
+}
+
+/// A channel of an image that drives a grass property, looked up through the
+/// `Mesh::ATTRIBUTE_UV_0` of the [`Grassable`] mesh.
+#[derive(Clone, Default)]
+pub struct GrassMap {
+    pub image: Handle<Image>,
+    pub channel: TextureChannel,
+}
+
+/// A level of detail of the grass, used from `distance` to the camera onwards.
+#[derive(Clone)]
+pub struct GrassLod {
+    pub distance: f32,
+    /// The blade mesh drawn at this level, or `None` to keep the mesh of the previous level.
+    pub mesh: Option<Handle<Mesh>>,
+    /// The fraction of blades drawn at this level. The density falls off linearly from the
+    /// previous level, and blades shrink away as they are thinned out instead of popping.
+    pub density: f32,
+}
+
 #[derive(Component)]
-pub struct Grassable {
+pub struct Grassable<M: GrassMaterial = StandardMaterial> {
     pub mesh: Handle<Mesh>,
     pub grass_mesh: Handle<Mesh>,
-    pub grass_material: Handle<StandardMaterial>,
+    pub grass_material: Handle<M>,
     pub density: f32,
+    /// Seed used when sampling grass points, so the same mesh and settings always produce the
+    /// same field.
+    pub seed: u64,
+    pub sampler: GrassSampler,
+    /// Which slopes grass grows on. The up direction is given in world space, and the grass is
+    /// resampled when turning the grassable changes which way is up on its mesh.
+    pub slope: SlopeLimit,
+    /// Scales the density of the grass, from none at `0` to `density` at `1`. Image maps in
+    /// formats that cannot be read on the CPU, see [`TextureMap::from_image`], are ignored.
+    pub density_map: Option<GrassMap>,
+    /// Scales the height of each blade by the map's value.
+    pub height_map: Option<GrassMap>,
+    /// Tints each blade by the map's color.
+    pub color_map: Option<Handle<Image>>,
+    /// Masks the grass by a vertex attribute of the mesh, such as painted vertex colors.
+    pub vertex_mask: Option<VertexMask>,
+    /// Width of the square chunks on the local XZ plane that the grass is split into. Each chunk
+    /// is frustum culled on its own.
+    pub chunk_size: f32,
+    /// Levels of detail that thin out distant grass or swap it to simpler blade meshes. Up to
+    /// four levels are used.
+    pub lods: Vec<GrassLod>,
+    /// Whether the blades cast shadows. Dense grass is drawn into every shadow map of every
+    /// light, which can be expensive.
+    pub cast_shadows: bool,
+    /// Whether the blades are darkened by the shadows of other geometry.
+    pub receive_shadows: bool,
+}
+
+impl<M: GrassMaterial> Default for Grassable<M> {
+    fn default() -> Self {
+        Self {
+            mesh: Handle::default(),
+            grass_mesh: Handle::default(),
+            grass_material: Handle::default(),
+            density: 1.,
+            seed: 0,
+            sampler: GrassSampler::default(),
+            slope: SlopeLimit {
+                max_angle: 0.75_f32.acos().to_degrees(),
+                ..default()
+            },
+            density_map: None,
+            height_map: None,
+            color_map: None,
+            vertex_mask: None,
+            chunk_size: 16.,
+            lods: Vec::new(),
+            cast_shadows: true,
+            receive_shadows: true,
+        }
+    }
 }
 
+/// Draws the grass of [`Grassable`]s with a [`StandardMaterial`].
 pub struct GrassPlugin;
 
 impl Plugin for GrassPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(InstancingPlugin::<Grass>::default())
-            .add_systems(PostStartup, spawn_grass_points);
+        app.add_plugins(GrassMaterialPlugin::<StandardMaterial>::default());
     }
 }
 
-fn spawn_grass_points(
-    mut commands: Commands,
-    meshes: Res<Assets<Mesh>>,
-    grassables_q: Query<(&Grassable, &Transform)>,
-) {
-    for (grassable, transform) in grassables_q.iter() {
-        let Some(mesh) = meshes.get(&grassable.mesh) else {
-            continue;
+/// Draws the grass of [`Grassable`]s with the [`GrassMaterial`] `M`.
+///
+/// Also adds the [`GrassWindPlugin`], [`GrassDisplacerPlugin`] and [`GrassFlattenPlugin`], which
+/// are shared by the grass of every material, unless they are already added.
+pub struct GrassMaterialPlugin<M>(PhantomData<M>);
+
+impl<M> Default for GrassMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: GrassMaterial> Plugin for GrassMaterialPlugin<M>
+where
+    <M as AsBindGroup>::Data: PartialEq + Eq + Hash + Clone,
+{
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<GrassWindPlugin>() {
+            app.add_plugins(GrassWindPlugin);
+        }
+        if !app.is_plugin_added::<GrassDisplacerPlugin>() {
+            app.add_plugins(GrassDisplacerPlugin);
+        }
+        if !app.is_plugin_added::<GrassFlattenPlugin>() {
+            app.add_plugins(GrassFlattenPlugin);
+        }
+        app.add_plugins(InstancingPlugin::<Grass<M>>::default())
+            .add_systems(
+                PostUpdate,
+                (
+                    (despawn_removed_grass::<M>, update_grass::<M>)
+                        .chain()
+                        .after(TransformSystem::TransformPropagate),
+                    update_flatten_bounds::<M>,
+                ),
+            );
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedGrassChunks<M>>()
+            .add_systems(ExtractSchedule, extract_grass_chunks::<M>)
+            .add_systems(
+                Render,
+                (
+                    prepare_grass_bindings::<M>.after(prepare_grass_wind),
+                    prepare_grass_flatten_bindings::<M>.after(prepare_flatten_textures),
+                )
+                    .in_set(RenderSet::PrepareResources),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<GrassWindBindings>()
+            .init_resource::<GrassDisplacementBuffer>()
+            .init_resource::<FlattenTextures>();
+    }
+}
+
+/// Links a chunk of grass instances to the [`Grassable`] entity its blades were sampled from.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GrassOf(pub Entity);
+
+impl<M: GrassMaterial> Grassable<M> {
+    /// The blade meshes of all levels of detail.
+    fn blade_meshes(&self) -> impl Iterator<Item = &Handle<Mesh>> {
+        std::iter::once(&self.grass_mesh)
+            .chain(self.lods.iter().filter_map(|lod| lod.mesh.as_ref()))
+    }
+
+    /// Whether this grassable is sampled from or drawn with `mesh`.
+    fn uses_mesh(&self, mesh: AssetId<Mesh>) -> bool {
+        self.mesh.id() == mesh || self.blade_meshes().any(|handle| handle.id() == mesh)
+    }
+
+    /// The levels of detail sorted by distance, with the mesh of each level filled in.
+    fn instance_lods(&self) -> Vec<InstanceLod> {
+        let mut lods = self.lods.clone();
+        lods.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let mut mesh = self.grass_mesh.clone();
+        lods.into_iter()
+            .map(|lod| {
+                if let Some(lod_mesh) = lod.mesh {
+                    mesh = lod_mesh;
+                }
+                InstanceLod {
+                    distance: lod.distance,
+                    mesh: mesh.clone(),
+                    density: lod.density,
+                }
+            })
+            .collect()
+    }
+
+    /// The up direction of the slope limit in the local space of the mesh, when the grassable is
+    /// placed by `transform`.
+    fn local_up(&self, transform: &GlobalTransform) -> UpDirection {
+        self.slope
+            .up
+            .transformed(transform.compute_matrix().inverse())
+    }
+
+    /// Whether any of the image maps of this grassable uses `image`.
+    fn uses_image(&self, image: AssetId<Image>) -> bool {
+        self.density_map.iter().any(|map| map.image.id() == image)
+            || self.height_map.iter().any(|map| map.image.id() == image)
+            || self.color_map.iter().any(|handle| handle.id() == image)
+    }
+}
+
+/// The space a blade of a grass mesh can take up around its root, at a scale of `1`.
+#[derive(Clone, Copy, Default)]
+struct BladeExtent {
+    /// Horizontal reach of the blade around any of its random rotations about the up axis.
+    radius: f32,
+    bottom: f32,
+    top: f32,
+}
+
+impl BladeExtent {
+    /// The extent of the largest of `meshes`.
+    fn new<'a>(meshes: impl IntoIterator<Item = &'a Mesh>) -> Self {
+        meshes
+            .into_iter()
+            .map(Self::from_mesh)
+            .fold(Self::default(), |a, b| Self {
+                radius: a.radius.max(b.radius),
+                bottom: a.bottom.min(b.bottom),
+                top: a.top.max(b.top),
+            })
+    }
+
+    fn from_mesh(mesh: &Mesh) -> Self {
+        let Some(aabb) = mesh.compute_aabb() else {
+            return Self::default();
+        };
+        let (min, max) = (Vec3::from(aabb.min()), Vec3::from(aabb.max()));
+        Self {
+            radius: Vec2::new(min.x.abs().max(max.x.abs()), min.z.abs().max(max.z.abs())).length(),
+            bottom: min.y.min(0.),
+            top: max.y.max(0.),
+        }
+    }
+
+    /// Radius of a sphere around the root of a blade that contains it, while the wind sways it by
+    /// at most `sway` per unit of height.
+    fn instance_radius(&self, sway: f32) -> f32 {
+        Vec2::new(self.radius, self.top.max(-self.bottom)).length() + sway * self.top
+    }
+
+    /// The extent of a chunk of blades.
+    fn chunk<M>(&self, grass: &[Grass<M>]) -> ChunkExtent {
+        let mut chunk = ChunkExtent {
+            blade: *self,
+            min: Vec3::splat(f32::MAX),
+            max: Vec3::splat(f32::MIN),
+            scale: 0.,
         };
-        let grass_points = UniformRandomSampler {
+        for blade in grass {
+            chunk.min = chunk.min.min(blade.position);
+            chunk.max = chunk.max.max(blade.position);
+            chunk.scale = chunk.scale.max(blade.scale);
+        }
+        chunk
+    }
+}
+
+/// The roots of the blades of a chunk of grass, which its bounds are padded around as the sway
+/// grows.
+#[derive(Component, Clone, Copy)]
+struct ChunkExtent {
+    blade: BladeExtent,
+    min: Vec3,
+    max: Vec3,
+    /// The largest scale of a blade.
+    scale: f32,
+}
+
+impl ChunkExtent {
+    /// The bounds of the chunk, with blades swaying by at most `sway` per unit of height.
+    fn aabb(&self, sway: f32) -> Aabb {
+        let blade = &self.blade;
+        let reach = (blade.radius + sway * blade.top) * self.scale;
+        Aabb::from_min_max(
+            self.min - Vec3::new(reach, -blade.bottom * self.scale, reach),
+            self.max + Vec3::new(reach, blade.top * self.scale, reach),
+        )
+    }
+}
+
+/// How much further than the sway they are needed for the bounds of chunks are padded, so that
+/// a rising wind only pads them again every so often.
+const SWAY_HEADROOM: f32 = 1.25;
+
+/// How far the normalized up direction of a grassable may drift before its grass is resampled,
+/// so that scaling or moving it does not resample it over rounding errors.
+const UP_TOLERANCE: f32 = 1e-4;
+
+/// Splits grass blades into the chunks of a `chunk_size` grid on the XZ plane.
+fn split_chunks<M>(grass: Vec<Grass<M>>, chunk_size: f32) -> HashMap<IVec2, Vec<Grass<M>>> {
+    let mut chunks: HashMap<IVec2, Vec<Grass<M>>> = HashMap::new();
+    for blade in grass {
+        let chunk = (Vec2::new(blade.position.x, blade.position.z) / chunk_size)
+            .floor()
+            .as_ivec2();
+        chunks.entry(chunk).or_default().push(blade);
+    }
+    chunks
+}
+
+/// Copies an image map to the CPU, or returns `None` while its image is not loaded. Maps in
+/// formats that cannot be read on the CPU are ignored with a warning, as `Some(None)`, so that the
+/// grass is still sampled without them.
+fn read_map(images: &Assets<Image>, image: &Handle<Image>) -> Option<Option<TextureMap>> {
+    let image = images.get(image)?;
+    let map = TextureMap::from_image(image);
+    if map.is_none() {
+        warn!(
+            "Unsupported grass map format {:?}, ignoring the map",
+            image.texture_descriptor.format
+        );
+    }
+    Some(map)
+}
+
+/// Samples the grass blades of a [`Grassable`], or returns `None` while any of its maps is not
+/// available.
+fn sample_grass<M: GrassMaterial>(
+    grassable: &Grassable<M>,
+    transform: &GlobalTransform,
+    mesh: &Mesh,
+    images: &Assets<Image>,
+) -> Option<Vec<Grass<M>>> {
+    let slope = SlopeLimit {
+        up: grassable.local_up(transform),
+        ..grassable.slope
+    };
+    let density_map = match &grassable.density_map {
+        Some(map) => read_map(images, &map.image)?.map(|texture| DensityMap {
+            texture,
+            channel: map.channel,
+        }),
+        None => None,
+    };
+    let height_map = match &grassable.height_map {
+        Some(map) => read_map(images, &map.image)?.map(|texture| (texture, map.channel)),
+        None => None,
+    };
+    let color_map = match &grassable.color_map {
+        Some(image) => read_map(images, image)?,
+        None => None,
+    };
+
+    let samples = match &grassable.sampler {
+        GrassSampler::Uniform => UniformRandomSampler {
             density: grassable.density,
-            threshold: 0.75,
+            slope,
+            density_map,
+            vertex_mask: grassable.vertex_mask,
+            seed: grassable.seed,
         }
-        .sample(mesh);
-        if grass_points.len() == 0 {
-            continue;
+        .sample_attributes(mesh),
+        GrassSampler::PoissonDisk { min_distance } => PoissonDiskSampler {
+            density: grassable.density,
+            slope,
+            density_map,
+            vertex_mask: grassable.vertex_mask,
+            seed: grassable.seed,
+            min_distance: *min_distance,
         }
-        commands.spawn((
-            grassable.grass_mesh.clone(),
-            grassable.grass_material.clone(),
-            SpatialBundle {
-                // TODO: setting the grass entity position to f32::MIN is a hack. Currently, 
-                // this entity is rendered as a single grass blade due to its mesh, material,
-                // transform, and visibility components. I couldn't come up with a clean solution
-                // for this problem, as removing any of these components prevents the instanced
-                // grass from rendering as well.
-                transform: Transform::from_xyz(0., f32::MIN, 0.),
-                ..SpatialBundle::INHERITED_IDENTITY
-            },
+        .sample_attributes(mesh),
+        GrassSampler::Custom(sampler) => sampler.sample_attributes(mesh),
+    };
+
+    Some(
+        samples
+            .iter()
+            .map(|sample| Grass {
+                position: sample.position,
+                scale: height_map
+                    .as_ref()
+                    .map_or(1., |(map, channel)| map.sample_channel(sample.uv, *channel)),
+                color: color_map.as_ref().map_or(Vec4::ONE, |map| {
+                    let [r, g, b, a] = map.sample(sample.uv).to_array();
+                    if map.is_srgb() {
+                        Vec4::from(Color::rgba(r, g, b, a).as_linear_rgba_f32())
+                    } else {
+                        Vec4::new(r, g, b, a)
+                    }
+                }),
+                marker: PhantomData,
+            })
+            .collect(),
+    )
+}
+
+/// Spawns the grass of a [`Grassable`], one child entity per chunk, with the blades in the local
+/// space of the grassable so that they follow its transform. Returns `false` if its meshes or
+/// image maps are not loaded yet.
+fn spawn_grass<M: GrassMaterial>(
+    commands: &mut Commands,
+    entity: Entity,
+    grassable: &Grassable<M>,
+    transform: &GlobalTransform,
+    meshes: &Assets<Mesh>,
+    images: &Assets<Image>,
+    sway: f32,
+) -> bool {
+    let Some(mesh) = meshes.get(&grassable.mesh) else {
+        return false;
+    };
+    let Some(blade_meshes) = grassable
+        .blade_meshes()
+        .map(|handle| meshes.get(handle))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+    let Some(mut grass) = sample_grass(grassable, transform, mesh, images) else {
+        return false;
+    };
+    // Distant levels of detail draw a prefix of each chunk, which should be a random subset.
+    grass.shuffle(&mut StdRng::seed_from_u64(grassable.seed));
+    let blade = BladeExtent::new(blade_meshes);
+    let lods = grassable.instance_lods();
+    for grass in split_chunks(grass, grassable.chunk_size).into_values() {
+        let extent = blade.chunk(&grass);
+        let mut chunk = commands.spawn((
+            GrassOf(entity),
+            SpatialBundle::INHERITED_IDENTITY,
+            extent.aabb(sway),
+            extent,
             InstanceData {
-                data: grass_points
-                    .iter()
-                    .map(|vec| Grass {
-                        position: transform.transform_point(*vec),
-                        scale: 1.,
-                    })
-                    .collect(),
+                data: grass,
                 mesh: grassable.grass_mesh.clone(),
+                material: grassable.grass_material.clone(),
+                lods: lods.clone(),
+                instance_radius: blade.instance_radius(sway),
             },
-            NoFrustumCulling,
         ));
+        chunk.set_parent(entity);
+        if !grassable.cast_shadows {
+            chunk.insert(NotShadowCaster);
+        }
+        if !grassable.receive_shadows {
+            chunk.insert(NotShadowReceiver);
+        }
+    }
+    true
+}
+
+/// The assets of one type that finished loading or were modified since the last update.
+struct AssetChanges<A: Asset> {
+    loaded: HashSet<AssetId<A>>,
+    modified: HashSet<AssetId<A>>,
+}
+
+impl<A: Asset> AssetChanges<A> {
+    fn read(events: &mut EventReader<AssetEvent<A>>) -> Self {
+        let mut changes = Self {
+            loaded: HashSet::new(),
+            modified: HashSet::new(),
+        };
+        for event in events.read() {
+            match event {
+                AssetEvent::Added { id } | AssetEvent::LoadedWithDependencies { id } => {
+                    changes.loaded.insert(*id);
+                }
+                AssetEvent::Modified { id } => {
+                    changes.modified.insert(*id);
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+/// Fits the [`GrassFlattenMap`]s of grassables to the XZ bounds of their meshes.
+fn update_flatten_bounds<M: GrassMaterial>(
+    meshes: Res<Assets<Mesh>>,
+    mut maps_q: Query<(Ref<Grassable<M>>, &mut GrassFlattenMap)>,
+) {
+    for (grassable, mut map) in &mut maps_q {
+        if map.bounds().is_some() && !grassable.is_changed() {
+            continue;
+        }
+        let Some(aabb) = meshes
+            .get(&grassable.mesh)
+            .and_then(|mesh| mesh.compute_aabb())
+        else {
+            continue;
+        };
+        let bounds = Rect::from_corners(Vec3::from(aabb.min()).xz(), Vec3::from(aabb.max()).xz());
+        if map.bounds() != Some(bounds) {
+            map.set_bounds(bounds);
+        }
+    }
+}
+
+/// Despawns the grass of entities that were despawned or lost their [`Grassable`].
+fn despawn_removed_grass<M: GrassMaterial>(
+    mut commands: Commands,
+    mut removed: RemovedComponents<Grassable<M>>,
+    grass_q: Query<(Entity, &GrassOf), With<InstanceData<Grass<M>>>>,
+) {
+    let removed: HashSet<Entity> = removed.read().collect();
+    if removed.is_empty() {
+        return;
+    }
+    for (entity, grass_of) in &grass_q {
+        if removed.contains(&grass_of.0) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Samples grass for new [`Grassable`]s, and resamples it whenever a grassable, one of its meshes
+/// or one of its image maps changes. Moving a grassable, or any of its ancestors, only resamples
+/// its grass when that turns the up direction of its slope limit relative to its mesh by more than
+/// [`UP_TOLERANCE`].
+///
+/// Grassables whose meshes or image maps are still loading are kept pending, and sampled once
+/// those assets finish loading.
+#[allow(clippy::too_many_arguments)]
+fn update_grass<M: GrassMaterial>(
+    mut commands: Commands,
+    mut pending: Local<HashSet<Entity>>,
+    mut sampled_up: Local<HashMap<Entity, UpDirection>>,
+    mut padded_sway: Local<f32>,
+    wind: Res<GrassWind>,
+    zones_q: Query<&GrassWindZone>,
+    displacers_q: Query<&GrassDisplacer>,
+    meshes: Res<Assets<Mesh>>,
+    images: Res<Assets<Image>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    grassables_q: Query<(Entity, Ref<Grassable<M>>, Ref<GlobalTransform>)>,
+    grass_q: Query<(Entity, &GrassOf), With<InstanceData<Grass<M>>>>,
+    mut chunks_q: Query<(&ChunkExtent, &mut Aabb, &mut InstanceData<Grass<M>>)>,
+) {
+    let mesh_changes = AssetChanges::read(&mut mesh_events);
+    let image_changes = AssetChanges::read(&mut image_events);
+    pending.retain(|entity| grassables_q.contains(*entity));
+    sampled_up.retain(|entity, _| grassables_q.contains(*entity));
+    // The bounds of existing chunks are padded in place when the sway outgrows them, and kept
+    // when it calms.
+    let max_sway = wind.max_sway() + max_zone_sway(&zones_q) + max_displacement_sway(&displacers_q);
+    if max_sway > *padded_sway {
+        *padded_sway = max_sway * SWAY_HEADROOM;
+        for (extent, mut aabb, mut instance_data) in &mut chunks_q {
+            *aabb = extent.aabb(*padded_sway);
+            instance_data.instance_radius = extent.blade.instance_radius(*padded_sway);
+        }
+    }
+
+    let dirty: HashSet<Entity> = grassables_q
+        .iter()
+        .filter(|(entity, grassable, transform)| {
+            let uses_any = |meshes: &HashSet<AssetId<Mesh>>, images: &HashSet<AssetId<Image>>| {
+                meshes.iter().any(|mesh| grassable.uses_mesh(*mesh))
+                    || images.iter().any(|image| grassable.uses_image(*image))
+            };
+            grassable.is_changed()
+                || (transform.is_changed()
+                    && !sampled_up.get(entity).is_some_and(|up| {
+                        up.abs_diff_eq(&grassable.local_up(transform), UP_TOLERANCE)
+                    }))
+                || uses_any(&mesh_changes.modified, &image_changes.modified)
+                || (pending.contains(entity)
+                    && uses_any(&mesh_changes.loaded, &image_changes.loaded))
+        })
+        .map(|(entity, _, _)| entity)
+        .collect();
+    if dirty.is_empty() {
+        return;
+    }
+
+    for (entity, grass_of) in &grass_q {
+        if dirty.contains(&grass_of.0) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    for entity in dirty {
+        let Ok((entity, grassable, transform)) = grassables_q.get(entity) else {
+            continue;
+        };
+        if spawn_grass(
+            &mut commands,
+            entity,
+            &grassable,
+            &transform,
+            &meshes,
+            &images,
+            *padded_sway,
+        ) {
+            pending.remove(&entity);
+            sampled_up.insert(entity, grassable.local_up(&transform));
+        } else {
+            pending.insert(entity);
+        }
     }
 }
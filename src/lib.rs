@@ -1,3 +1,14 @@
+pub mod culling;
+pub mod displacement;
+pub mod flatten;
 pub mod grass;
 mod render;
 pub mod sampling;
+pub mod texture;
+pub mod wind;
+
+pub use render::instancing::{
+    packed_vertex_attributes, EntityInstanceBindings, InstanceData, InstanceLod, InstancedMaterial,
+    InstancedPhase, InstancingPlugin, SharedInstanceBindings, FIRST_INSTANCE_LOCATION,
+    INSTANCE_INDEX_LOCATION, MAX_INSTANCE_LODS, SHARED_INSTANCE_BINDING,
+};
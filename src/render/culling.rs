@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
+    BufferBindingType, CachedComputePipelineId, ComputePipeline, ComputePipelineDescriptor,
+    PipelineCache, ShaderStages,
+};
+use bevy::render::renderer::RenderDevice;
+use bytemuck::{Pod, Zeroable};
+
+use super::instancing::InstanceLodUniform;
+
+/// Instances culled by one workgroup of the culling pass.
+pub const CULLING_WORKGROUP_SIZE: u32 = 64;
+
+/// The compute pipeline that culls single instances with
+/// [`InstanceCulling::Instance`](crate::culling::InstanceCulling::Instance).
+#[derive(Resource)]
+pub struct InstanceCullingPipeline {
+    pub layout: BindGroupLayout,
+    /// `None` where compute shaders are not supported.
+    pub pipeline: Option<CachedComputePipelineId>,
+    _instancing_shader: Handle<Shader>,
+}
+
+impl FromWorld for InstanceCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let asset_server = world.resource::<AssetServer>();
+
+        let storage = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance culling layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+                storage(4, false),
+            ],
+        });
+
+        // WebGL2 allows neither compute workgroups nor storage buffers.
+        let limits = render_device.limits();
+        let supported = limits.max_compute_workgroups_per_dimension > 0
+            && limits.max_storage_buffers_per_shader_stage >= 4;
+        let pipeline = supported.then(|| {
+            world
+                .resource::<PipelineCache>()
+                .queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("instance culling pipeline".into()),
+                    layout: vec![layout.clone()],
+                    push_constant_ranges: Vec::new(),
+                    shader: asset_server.load("shaders/instance_culling.wgsl"),
+                    shader_defs: Vec::new(),
+                    entry_point: "cull".into(),
+                })
+        });
+
+        Self {
+            layout,
+            pipeline,
+            _instancing_shader: asset_server.load("shaders/instancing.wgsl"),
+        }
+    }
+}
+
+impl InstanceCullingPipeline {
+    /// The compute pipeline, once it is compiled.
+    pub fn get<'a>(&self, pipeline_cache: &'a PipelineCache) -> Option<&'a ComputePipeline> {
+        let id = self.pipeline?;
+        // Queued pipelines are only added to the cache once its queue is processed.
+        if pipeline_cache.pipelines().count() <= id.id() {
+            return None;
+        }
+        pipeline_cache.get_compute_pipeline(id)
+    }
+}
+
+/// The parameters of culling the instances of one entity for one view, as read by
+/// `instance_culling.wgsl`.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct CullingUniform {
+    pub world_from_local: Mat4,
+    /// The frustum's half spaces, without the far plane.
+    pub planes: [Vec4; 5],
+    pub view_position: Vec3,
+    pub instance_radius: f32,
+    pub lod: InstanceLodUniform,
+    /// Number of instances at the start of the instance buffer that are culled.
+    pub culled_count: u32,
+    /// Size of an instance in `u32`s.
+    pub instance_stride: u32,
+    pub _padding: [u32; 2],
+}
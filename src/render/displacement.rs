@@ -0,0 +1,121 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{Buffer, BufferInitDescriptor, BufferUsages};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::Extract;
+use bytemuck::{Pod, Zeroable};
+
+use crate::displacement::{DisplacerTrails, GrassDisplacer, MAX_DISPLACEMENTS};
+
+/// A [`GrassDisplacer`] or a point of its trail, as read by `displacement_offset` in
+/// `displacement.wgsl`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+struct DisplacementUniform {
+    position: Vec3,
+    radius: f32,
+    strength: f32,
+    _padding: [u32; 3],
+}
+
+/// The displacements that bend grass this frame.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct DisplacementsUniform {
+    count: u32,
+    _padding: [u32; 3],
+    displacements: [DisplacementUniform; MAX_DISPLACEMENTS],
+}
+
+/// The displacers of the main world, then their trails from the newest point on.
+#[derive(Resource, Default)]
+pub struct ExtractedDisplacements {
+    displacements: Vec<DisplacementUniform>,
+}
+
+/// The uniform buffer of the displacements, shared by the grass of all materials.
+#[derive(Resource)]
+pub struct GrassDisplacementBuffer {
+    pub buffer: Buffer,
+    _displacement_shader: Handle<Shader>,
+}
+
+impl FromWorld for GrassDisplacementBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let buffer =
+            world
+                .resource::<RenderDevice>()
+                .create_buffer_with_data(&BufferInitDescriptor {
+                    label: Some("grass displacement buffer"),
+                    contents: bytemuck::bytes_of(&DisplacementsUniform::zeroed()),
+                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                });
+        Self {
+            buffer,
+            _displacement_shader: world
+                .resource::<AssetServer>()
+                .load("shaders/displacement.wgsl"),
+        }
+    }
+}
+
+pub(crate) fn extract_displacements(
+    mut extracted: ResMut<ExtractedDisplacements>,
+    time: Extract<Res<Time>>,
+    trails: Extract<Res<DisplacerTrails>>,
+    displacers_q: Extract<Query<(&GrassDisplacer, &GlobalTransform)>>,
+) {
+    let now = time.elapsed_seconds();
+    let displacers = displacers_q.iter().map(|(displacer, transform)| {
+        (
+            transform.translation(),
+            displacer.radius,
+            displacer.strength,
+        )
+    });
+    let mut trail_points: Vec<_> = trails
+        .0
+        .values()
+        .flat_map(|trail| {
+            trail
+                .points
+                .iter()
+                .map(move |point| (point, trail.radius, trail.strength_at(point, now)))
+        })
+        .collect();
+    trail_points.sort_by(|(a, ..), (b, ..)| b.pressed_at.total_cmp(&a.pressed_at));
+    let displacements: Vec<_> = displacers
+        .chain(
+            trail_points
+                .into_iter()
+                .map(|(point, radius, strength)| (point.position, radius, strength)),
+        )
+        .take(MAX_DISPLACEMENTS)
+        .map(|(position, radius, strength)| DisplacementUniform {
+            position,
+            radius,
+            strength,
+            _padding: [0; 3],
+        })
+        .collect();
+    // Left untouched while no displacer moves and no trail fades, so that `prepare_displacements`
+    // skips the upload.
+    if extracted.displacements != displacements {
+        extracted.displacements = displacements;
+    }
+}
+
+pub(crate) fn prepare_displacements(
+    displacements: Res<ExtractedDisplacements>,
+    displacement_buffer: Option<Res<GrassDisplacementBuffer>>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(displacement_buffer) = displacement_buffer.filter(|_| displacements.is_changed())
+    else {
+        return;
+    };
+    let mut uniform = DisplacementsUniform::zeroed();
+    uniform.count = displacements.displacements.len() as u32;
+    uniform.displacements[..displacements.displacements.len()]
+        .copy_from_slice(&displacements.displacements);
+    render_queue.write_buffer(&displacement_buffer.buffer, 0, bytemuck::bytes_of(&uniform));
+}
@@ -0,0 +1,197 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{
+    AddressMode, Buffer, BufferInitDescriptor, BufferUsages, Extent3d, FilterMode,
+    ImageCopyTexture, ImageDataLayout, Origin3d, Sampler, SamplerDescriptor, Texture,
+    TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureView,
+    TextureViewDescriptor,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::Extract;
+use bevy::utils::{HashMap, HashSet};
+use bytemuck::{Pod, Zeroable};
+
+use crate::flatten::GrassFlattenMap;
+
+/// The bounds of a [`GrassFlattenMap`], as read by `flatness` in `flatten.wgsl`.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct FlattenUniform {
+    min: Vec2,
+    size: Vec2,
+}
+
+/// A [`GrassFlattenMap`] that changed since it was last extracted.
+struct ExtractedFlattenMap {
+    resolution: UVec2,
+    bounds: Rect,
+    /// The flatness of every texel, as `R8Unorm`.
+    texels: Vec<u8>,
+}
+
+/// The flatten maps of the main world, by the entity of their
+/// [`Grassable`](crate::grass::Grassable).
+#[derive(Resource, Default)]
+pub struct ExtractedFlattenMaps {
+    changed: HashMap<Entity, ExtractedFlattenMap>,
+    alive: HashSet<Entity>,
+}
+
+/// A flatten map on the GPU.
+pub struct FlattenTexture {
+    texture: Texture,
+    pub view: TextureView,
+    pub uniform: Buffer,
+    resolution: UVec2,
+}
+
+impl FlattenTexture {
+    fn new(render_device: &RenderDevice, resolution: UVec2) -> Self {
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("grass flatten texture"),
+            size: Extent3d {
+                width: resolution.x,
+                height: resolution.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("grass flatten buffer"),
+            // Until the map is written, no grass lies within its bounds.
+            contents: bytemuck::bytes_of(&FlattenUniform::zeroed()),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        Self {
+            view: texture.create_view(&TextureViewDescriptor::default()),
+            texture,
+            uniform,
+            resolution,
+        }
+    }
+
+    fn write(&self, render_queue: &RenderQueue, map: &ExtractedFlattenMap) {
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            &map.texels,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(map.resolution.x),
+                rows_per_image: None,
+            },
+            Extent3d {
+                width: map.resolution.x,
+                height: map.resolution.y,
+                depth_or_array_layers: 1,
+            },
+        );
+        let uniform = FlattenUniform {
+            min: map.bounds.min,
+            size: map.bounds.size(),
+        };
+        render_queue.write_buffer(&self.uniform, 0, bytemuck::bytes_of(&uniform));
+    }
+}
+
+/// The flatten maps on the GPU, by the entity of their grassable. It is only marked as changed
+/// when textures are created or dropped, not when they are written.
+#[derive(Resource)]
+pub struct FlattenTextures {
+    pub textures: HashMap<Entity, FlattenTexture>,
+    /// Bound for grass without a flatten map, which covers no grass.
+    pub fallback: FlattenTexture,
+    pub sampler: Sampler,
+    _flatten_shader: Handle<Shader>,
+}
+
+impl FromWorld for FlattenTextures {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("grass flatten sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+        Self {
+            textures: HashMap::new(),
+            fallback: FlattenTexture::new(render_device, UVec2::ONE),
+            sampler,
+            _flatten_shader: world.resource::<AssetServer>().load("shaders/flatten.wgsl"),
+        }
+    }
+}
+
+pub(crate) fn extract_flatten_maps(
+    mut extracted: ResMut<ExtractedFlattenMaps>,
+    maps_q: Extract<Query<(Entity, Ref<GrassFlattenMap>)>>,
+) {
+    extracted.changed.clear();
+    extracted.alive.clear();
+    for (entity, map) in &maps_q {
+        let Some(bounds) = map.bounds() else {
+            continue;
+        };
+        extracted.alive.insert(entity);
+        if map.is_changed() {
+            let texels = map
+                .values()
+                .iter()
+                .map(|value| (value.clamp(0., 1.) * 255.).round() as u8)
+                .collect();
+            extracted.changed.insert(
+                entity,
+                ExtractedFlattenMap {
+                    resolution: map.resolution(),
+                    bounds,
+                    texels,
+                },
+            );
+        }
+    }
+}
+
+/// Writes the flatten maps that changed to their textures, creating the textures of new maps
+/// and dropping those of removed maps.
+pub(crate) fn prepare_flatten_textures(
+    extracted: Res<ExtractedFlattenMaps>,
+    flatten_textures: Option<ResMut<FlattenTextures>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(mut flatten_textures) = flatten_textures else {
+        return;
+    };
+    if flatten_textures
+        .textures
+        .keys()
+        .any(|entity| !extracted.alive.contains(entity))
+    {
+        flatten_textures
+            .textures
+            .retain(|entity, _| extracted.alive.contains(entity));
+    }
+    for (entity, map) in &extracted.changed {
+        let reusable = flatten_textures
+            .textures
+            .get(entity)
+            .is_some_and(|texture| texture.resolution == map.resolution);
+        if !reusable {
+            flatten_textures
+                .textures
+                .insert(*entity, FlattenTexture::new(&render_device, map.resolution));
+        }
+        flatten_textures.textures[entity].write(&render_queue, map);
+    }
+}
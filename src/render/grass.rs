@@ -0,0 +1,88 @@
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+use bevy::render::render_resource::OwnedBindingResource;
+use bevy::render::Extract;
+use bevy::utils::HashMap;
+
+use super::displacement::GrassDisplacementBuffer;
+use super::flatten::FlattenTextures;
+use super::instancing::{EntityInstanceBindings, InstanceData, SharedInstanceBindings};
+use super::wind::GrassWindBindings;
+use crate::grass::{Grass, GrassMaterial, GrassOf};
+
+/// The grassable of every chunk of grass drawn with `M`.
+#[derive(Resource)]
+pub struct ExtractedGrassChunks<M> {
+    chunks: HashMap<Entity, Entity>,
+    marker: PhantomData<M>,
+}
+
+impl<M> Default for ExtractedGrassChunks<M> {
+    fn default() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn extract_grass_chunks<M: GrassMaterial>(
+    mut extracted: ResMut<ExtractedGrassChunks<M>>,
+    chunks_q: Extract<Query<(Entity, &GrassOf), With<InstanceData<Grass<M>>>>>,
+) {
+    let chunks: HashMap<Entity, Entity> = chunks_q
+        .iter()
+        .map(|(entity, grass_of)| (entity, grass_of.0))
+        .collect();
+    // Assigned only when chunks were spawned or despawned, as the flatten bindings of every chunk
+    // are handed over again whenever it changes.
+    if extracted.chunks != chunks {
+        extracted.chunks = chunks;
+    }
+}
+
+/// Hands the wind and displacement bindings to the instancing of grass drawn with `M` when they
+/// change, in the order of the layout entries of [`Grass`].
+pub(crate) fn prepare_grass_bindings<M: GrassMaterial>(
+    mut commands: Commands,
+    wind_bindings: Res<GrassWindBindings>,
+    displacement_buffer: Res<GrassDisplacementBuffer>,
+) {
+    if wind_bindings.is_changed() || displacement_buffer.is_changed() {
+        let mut resources = wind_bindings.resources();
+        resources.push(OwnedBindingResource::Buffer(
+            displacement_buffer.buffer.clone(),
+        ));
+        commands.insert_resource(SharedInstanceBindings::<Grass<M>>::new(resources));
+    }
+}
+
+/// Binds the flatten map of its grassable to every chunk of grass drawn with `M`, or an empty
+/// map to the chunks of grassables without one.
+pub(crate) fn prepare_grass_flatten_bindings<M: GrassMaterial>(
+    chunks: Res<ExtractedGrassChunks<M>>,
+    flatten_textures: Res<FlattenTextures>,
+    mut entity_bindings: ResMut<EntityInstanceBindings<Grass<M>>>,
+) {
+    if !chunks.is_changed() && !flatten_textures.is_changed() {
+        return;
+    }
+    entity_bindings.bindings = chunks
+        .chunks
+        .iter()
+        .map(|(chunk, grassable)| {
+            let texture = flatten_textures
+                .textures
+                .get(grassable)
+                .unwrap_or(&flatten_textures.fallback);
+            let resources = vec![
+                OwnedBindingResource::TextureView(texture.view.clone()),
+                OwnedBindingResource::Sampler(flatten_textures.sampler.clone()),
+                OwnedBindingResource::Buffer(texture.uniform.clone()),
+            ];
+            (*chunk, resources)
+        })
+        .collect();
+}
@@ -1,58 +1,297 @@
+use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::Range;
 
-use bevy::core_pipeline::core_3d::Transparent3d;
-use bevy::ecs::query::QueryItem;
-use bevy::ecs::system::lifetimeless::{Read, SRes};
+use bevy::core_pipeline::core_3d::{Opaque3d, Transparent3d};
+use bevy::core_pipeline::prepass::{
+    DepthPrepass, MotionVectorPrepass, NormalPrepass, Opaque3dPrepass,
+};
+use bevy::ecs::system::lifetimeless::SRes;
 use bevy::ecs::system::SystemParamItem;
 use bevy::pbr::{
-    MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMaterialBindGroup, SetMeshBindGroup,
-    SetMeshViewBindGroup,
+    extract_meshes, CascadesVisibleEntities, CubemapVisibleEntities, ExtractedDirectionalLight,
+    ExtractedPointLight, LightEntity, MaterialBindGroupId, MaterialPipelineKey, Mesh3d, MeshFlags,
+    MeshPipeline, MeshPipelineKey, MeshTransforms, NotShadowCaster, NotShadowReceiver,
+    PrepassPipeline, PrepassViewBindGroup, RenderMaterials, RenderMeshInstance,
+    RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup, SetPrepassViewBindGroup, Shadow,
+    ViewLightEntities,
 };
 use bevy::prelude::*;
-use bevy::render::extract_component::{ExtractComponent, ExtractComponentPlugin};
+use bevy::render::extract_resource::ExtractResourcePlugin;
 use bevy::render::mesh::{GpuBufferInfo, MeshVertexBufferLayout};
+use bevy::render::primitives::{Aabb, Frustum};
 use bevy::render::render_asset::RenderAssets;
 use bevy::render::render_phase::{
     AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase,
     SetItemPipeline, TrackedRenderPass,
 };
 use bevy::render::render_resource::{
-    BindGroupLayout, Buffer, BufferInitDescriptor, BufferUsages, PipelineCache,
-    RenderPipelineDescriptor, SpecializedMeshPipeline, SpecializedMeshPipelineError,
-    SpecializedMeshPipelines, VertexAttribute, VertexBufferLayout, VertexFormat, VertexStepMode,
+    AsBindGroup, BindGroup, BindGroupEntries, BindGroupEntry, BindGroupLayout,
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType,
+    BufferDescriptor, BufferId, BufferInitDescriptor, BufferUsages, CommandEncoderDescriptor,
+    ComputePassDescriptor, OwnedBindingResource, PipelineCache, RenderPipelineDescriptor,
+    ShaderStages, SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
+    VertexAttribute, VertexBufferLayout, VertexFormat, VertexStepMode,
 };
-use bevy::render::renderer::RenderDevice;
-use bevy::render::view::ExtractedView;
-use bevy::render::{Render, RenderApp, RenderSet};
-use bytemuck::Pod;
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::view::{ExtractedView, VisibleEntities};
+use bevy::render::{Extract, ExtractSchedule, Render, RenderApp, RenderSet};
+use bevy::utils::HashMap;
+use bytemuck::{Pod, Zeroable};
 
-pub trait InstancedMaterial: Send + Sync + Clone + Pod
-where
-    Self::M: Material,
-{
-    type M;
+use super::culling::{CullingUniform, InstanceCullingPipeline, CULLING_WORKGROUP_SIZE};
+use crate::culling::{cull_instances, CullingView, InstanceCulling};
+
+/// The data of a single instance, uploaded as is into a per-instance vertex buffer.
+///
+/// Past its start, the layout of an instance is up to the implementor. Every instance must start
+/// with its position in the local space of its entity and its scale, as four `f32`s, and be a
+/// multiple of four bytes in size: culling reads the position and scale of instances on the CPU,
+/// and from the instance buffer in the culling pass. [`InstancingPlugin`] panics for types that
+/// are too small to hold them.
+pub trait InstancedMaterial: Send + Sync + Clone + Pod {
+    type M: Material;
 
     fn shader_path() -> &'static str;
 
+    /// The attributes the instancing shaders read from each instance, see
+    /// [`packed_vertex_attributes`]. They must sit at [`FIRST_INSTANCE_LOCATION`] or past it:
+    /// the main pass and the prepasses place the attributes of the mesh at different locations
+    /// below [`INSTANCE_INDEX_LOCATION`], which holds the index of the instance. The attributes
+    /// may read the position and scale at the start of each instance, but need not.
+    fn vertex_attributes() -> Vec<VertexAttribute>;
+
+    /// The phase instances are drawn in by the main pass.
+    fn phase() -> InstancedPhase {
+        InstancedPhase::Opaque
+    }
+
+    /// The shader that draws instances into shadow maps, and [`InstancedPhase::Opaque`]
+    /// instances into the depth, normal and motion vector prepasses, or `None` to leave them out
+    /// of both. It is specialized with the `PREPASS_PIPELINE` shader def, and its bind groups are
+    /// those of the main pass except for the prepass view bind group.
+    ///
+    /// Shadow maps are drawn with the `INSTANCE_SHADOW_PASS` shader def as well. The view is then
+    /// a light rather than the camera, so instances should not be faded by their level of detail.
+    fn prepass_shader_path() -> Option<&'static str> {
+        None
+    }
+
     fn material_bind_group_layout<M: Material>(render_device: &RenderDevice) -> BindGroupLayout {
         M::bind_group_layout(render_device)
     }
+
+    /// Layout entries of resources shared by every instance entity, bound in group 3 from
+    /// [`SHARED_INSTANCE_BINDING`] onwards. The resources are given by the
+    /// [`SharedInstanceBindings`] in the render world, and no instances are drawn until it is
+    /// inserted.
+    fn shared_layout_entries() -> Vec<BindGroupLayoutEntry> {
+        Vec::new()
+    }
+
+    /// Layout entries of resources of each instance entity of its own, bound in group 3 after
+    /// the [`InstancedMaterial::shared_layout_entries`]. The resources are given by the
+    /// [`EntityInstanceBindings`] in the render world, and the instances of an entity are not
+    /// drawn until it has them.
+    fn entity_layout_entries() -> Vec<BindGroupLayoutEntry> {
+        Vec::new()
+    }
 }
 
+/// The first binding of group 3 that [`InstancedMaterial::shared_layout_entries`] can use. The
+/// bindings before it hold the level of detail parameters and transform of the entity.
+pub const SHARED_INSTANCE_BINDING: u32 = 2;
+
+/// The resources shared by every entity of the [`InstancedMaterial`] `D`, in the order of its
+/// [`InstancedMaterial::shared_layout_entries`]. The bind groups of the entities are created
+/// again whenever this resource changes.
+#[derive(Resource)]
+pub struct SharedInstanceBindings<D> {
+    pub resources: Vec<OwnedBindingResource>,
+    marker: PhantomData<D>,
+}
+
+impl<D> SharedInstanceBindings<D> {
+    pub fn new(resources: Vec<OwnedBindingResource>) -> Self {
+        Self {
+            resources,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// The resources of each entity of the [`InstancedMaterial`] `D`, in the order of its
+/// [`InstancedMaterial::entity_layout_entries`]. The bind groups of the entities are created
+/// again whenever this resource changes, so it should only be mutated when its resources do.
+#[derive(Resource)]
+pub struct EntityInstanceBindings<D> {
+    pub bindings: HashMap<Entity, Vec<OwnedBindingResource>>,
+    marker: PhantomData<D>,
+}
+
+impl<D> Default for EntityInstanceBindings<D> {
+    fn default() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+/// The render phase of the main pass that instances are drawn in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstancedPhase {
+    /// Drawn front to back with depth writes, and into the prepasses.
+    #[default]
+    Opaque,
+    /// Alpha blended, and drawn back to front after opaque geometry.
+    Transparent,
+}
+
+/// The shader location of the index of an instance in its [`InstanceData`], after the skinning
+/// attributes of the mesh.
+pub const INSTANCE_INDEX_LOCATION: u32 = 8;
+
+/// The first shader location past the attributes of the mesh and [`INSTANCE_INDEX_LOCATION`],
+/// where the attributes of instances can start.
+pub const FIRST_INSTANCE_LOCATION: u32 = INSTANCE_INDEX_LOCATION + 1;
+
+/// Attributes of `formats` packed one after another from the start of an instance, at consecutive
+/// shader locations from `first_location`.
+pub fn packed_vertex_attributes(
+    first_location: u32,
+    formats: &[VertexFormat],
+) -> Vec<VertexAttribute> {
+    let mut offset = 0;
+    formats
+        .iter()
+        .zip(first_location..)
+        .map(|(&format, shader_location)| {
+            let attribute = VertexAttribute {
+                format,
+                offset,
+                shader_location,
+            };
+            offset += format.size();
+            attribute
+        })
+        .collect()
+}
+
+/// The most levels of detail of an [`InstanceData`] that are used.
+pub const MAX_INSTANCE_LODS: usize = 4;
+
+/// Fraction of the instances over which thinned out instances fade. Mirrors `LOD_FADE` in the
+/// instancing shaders.
+const LOD_FADE: f32 = 0.05;
+
+/// Per-instance data drawn with `mesh` and `material` by the [`InstancingPlugin`].
+///
+/// Instances are placed in the local space of the entity, and follow its transform. The entity
+/// only needs a [`SpatialBundle`] besides this component. It is not a regular mesh
+/// entity, so it is not also drawn as a single mesh by Bevy's PBR pipeline. An [`Aabb`] on the
+/// entity is used both for frustum culling and to pick its level of detail.
 #[derive(Component, Clone)]
-pub struct InstanceData<D> {
+pub struct InstanceData<D: InstancedMaterial> {
+    /// Distant levels of detail thin out instances from the end of `data` first, so it should be
+    /// in random order.
     pub data: Vec<D>,
     pub mesh: Handle<Mesh>,
+    pub material: Handle<D::M>,
+    /// Levels of detail sorted by distance. Only the first [`MAX_INSTANCE_LODS`] are used.
+    pub lods: Vec<InstanceLod>,
+    /// Radius of a sphere around the position of an instance that contains its mesh at a scale
+    /// of `1`. Used to cull single instances.
+    pub instance_radius: f32,
+}
+
+/// A level of detail of an [`InstanceData`], used from `distance` to the camera onwards.
+#[derive(Clone)]
+pub struct InstanceLod {
+    pub distance: f32,
+    pub mesh: Handle<Mesh>,
+    /// The fraction of instances drawn at this level. The density falls off linearly from the
+    /// previous level, and instances shrink away as they are thinned out.
+    pub density: f32,
 }
 
-impl<D: InstancedMaterial> ExtractComponent for InstanceData<D> {
-    type Query = &'static InstanceData<D>;
-    type Filter = ();
-    type Out = Self;
+impl<D: InstancedMaterial> InstanceData<D> {
+    fn lods(&self) -> &[InstanceLod] {
+        &self.lods[..self.lods.len().min(MAX_INSTANCE_LODS)]
+    }
+
+    /// The mesh drawn `distance` away from the camera.
+    fn lod_mesh(&self, distance: f32) -> &Handle<Mesh> {
+        self.lods()
+            .iter()
+            .rev()
+            .find(|lod| lod.distance <= distance)
+            .map_or(&self.mesh, |lod| &lod.mesh)
+    }
+
+    /// The density of instances `distance` away from the camera. Mirrors `lod_density` in the
+    /// instancing shaders.
+    fn lod_density(&self, distance: f32) -> f32 {
+        let mut density = 1.;
+        let mut start = 0.;
+        for lod in self.lods() {
+            if distance < lod.distance {
+                let t = (distance - start) / (lod.distance - start);
+                return density + (lod.density - density) * t;
+            }
+            density = lod.density;
+            start = lod.distance;
+        }
+        density
+    }
+
+    /// The fraction of instances that are drawn, partly faded or not, `distance` away from the
+    /// camera.
+    fn lod_keep_fraction(&self, distance: f32) -> f32 {
+        keep_fraction(self.lod_density(distance))
+    }
 
-    fn extract_component(item: QueryItem<'_, Self::Query>) -> Option<Self::Out> {
-        Some(item.clone())
+    /// The number of instances to draw for an entity between `near` and `far` from the camera.
+    fn lod_instance_count(&self, near: f32, far: f32) -> u32 {
+        let density = self
+            .lods()
+            .iter()
+            .filter(|lod| (near..far).contains(&lod.distance))
+            .map(|lod| lod.density)
+            .fold(self.lod_density(near).max(self.lod_density(far)), f32::max);
+        (self.data.len() as f32 * keep_fraction(density)).ceil() as u32
     }
+
+    /// The mesh and number of instances drawn for an entity within `bounds`, seen from
+    /// `view_position`. Entities without bounds are drawn in full.
+    fn lod_selection(
+        &self,
+        bounds: Option<&InstanceBounds>,
+        view_position: Vec3,
+    ) -> (AssetId<Mesh>, u32) {
+        let (distance, near, far) = bounds.map_or((0., 0., f32::INFINITY), |bounds| {
+            let distance = view_position.distance(bounds.center);
+            (
+                distance,
+                (distance - bounds.radius).max(0.),
+                distance + bounds.radius,
+            )
+        });
+        (
+            self.lod_mesh(distance).id(),
+            self.lod_instance_count(near, far),
+        )
+    }
+}
+
+fn keep_fraction(density: f32) -> f32 {
+    (density * (1. + LOD_FADE)).clamp(0., 1.)
+}
+
+/// The position and scale an instance starts with, which [`InstancingPlugin`] checks it has room
+/// for.
+fn position_scale<D: InstancedMaterial>(instance: &D) -> Vec4 {
+    bytemuck::pod_read_unaligned(&bytemuck::bytes_of(instance)[..16])
 }
 
 pub struct InstancingPlugin<D>(PhantomData<D>);
@@ -60,24 +299,83 @@ pub struct InstancingPlugin<D>(PhantomData<D>);
 impl<D: 'static> Plugin for InstancingPlugin<D>
 where
     D: InstancedMaterial,
+    <D::M as AsBindGroup>::Data: PartialEq + Eq + Hash + Clone,
 {
     fn build(&self, app: &mut App) {
-        app.add_plugins((ExtractComponentPlugin::<InstanceData<D>>::default(),));
+        let size = std::mem::size_of::<D>();
+        // `usize::is_multiple_of` needs a newer compiler than the rest of the crate.
+        #[allow(clippy::manual_is_multiple_of)]
+        let whole_f32s = size % 4 == 0;
+        assert!(
+            size >= 16 && whole_f32s,
+            "instances of `{}` are {size} bytes, but must start with their position and scale as \
+             four `f32`s and be a multiple of four bytes in size",
+            std::any::type_name::<D>(),
+        );
+        // Prepares the bind groups of the materials, which are only used by instance entities.
+        if !app.is_plugin_added::<MaterialPlugin<D::M>>() {
+            app.add_plugins(MaterialPlugin::<D::M>::default());
+        }
+        if !app.is_plugin_added::<ExtractResourcePlugin<InstanceCulling>>() {
+            app.init_resource::<InstanceCulling>()
+                .add_plugins(ExtractResourcePlugin::<InstanceCulling>::default());
+        }
         app.sub_app_mut(RenderApp)
-            .add_render_command::<Transparent3d, DrawInstanced<D::M>>()
+            .init_resource::<InstanceCulling>()
+            .add_render_command::<Opaque3d, DrawInstanced<D>>()
+            .add_render_command::<Transparent3d, DrawInstanced<D>>()
+            .add_render_command::<Opaque3dPrepass, DrawInstancedPrepass<D>>()
+            .add_render_command::<Shadow, DrawInstancedPrepass<D>>()
             .init_resource::<SpecializedMeshPipelines<InstancingPipeline<D>>>()
+            .init_resource::<SpecializedMeshPipelines<InstancingPrepassPipeline<D>>>()
+            .init_resource::<RenderInstances<D>>()
+            .init_resource::<EntityInstanceBindings<D>>()
+            .init_resource::<InstanceLodSelections<D>>()
+            .init_resource::<CulledInstanceBuffers<D>>()
+            .add_systems(
+                ExtractSchedule,
+                (
+                    extract_instance_data::<D>,
+                    extract_instanced_meshes::<D>.after(extract_meshes),
+                ),
+            )
             .add_systems(
                 Render,
                 (
                     queue_custom::<D>.in_set(RenderSet::QueueMeshes),
-                    (prepare_instance_buffers::<D>,).in_set(RenderSet::PrepareResources),
+                    queue_prepass::<D>
+                        .after(queue_custom::<D>)
+                        .in_set(RenderSet::QueueMeshes)
+                        .run_if(resource_exists::<InstancingPrepassPipeline<D>>()),
+                    queue_shadows::<D>
+                        .after(queue_custom::<D>)
+                        .in_set(RenderSet::QueueMeshes)
+                        .run_if(resource_exists::<InstancingPrepassPipeline<D>>()),
+                    (
+                        prepare_instance_buffers::<D>,
+                        prepare_instance_transforms::<D>.after(prepare_instance_buffers::<D>),
+                    )
+                        .in_set(RenderSet::PrepareResources),
+                    (
+                        prepare_instance_bind_groups::<D>,
+                        prepare_culled_instances::<D>,
+                    )
+                        .in_set(RenderSet::PrepareBindGroups),
                 ),
             );
     }
 
     fn finish(&self, app: &mut App) {
-        app.sub_app_mut(RenderApp)
-            .init_resource::<InstancingPipeline<D>>();
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<InstancingPipeline<D>>()
+            .init_resource::<InstanceCullingPipeline>();
+        // The prepass view bind group is prepared by Bevy's prepass plugins.
+        if D::prepass_shader_path().is_some()
+            && render_app.world.contains_resource::<PrepassViewBindGroup>()
+        {
+            render_app.init_resource::<InstancingPrepassPipeline<D>>();
+        }
     }
 }
 
@@ -87,39 +385,429 @@ impl<D> Default for InstancingPlugin<D> {
     }
 }
 
-type DrawInstanced<M> = (
+type DrawInstanced<D> = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
-    SetMaterialBindGroup<M, 1>,
+    SetInstancedMaterialBindGroup<D, 1>,
     SetMeshBindGroup<2>,
-    DrawMeshInstanced,
+    SetInstanceBindGroup<D, 3>,
+    DrawMeshInstanced<D>,
 );
 
-struct DrawMeshInstanced;
+type DrawInstancedPrepass<D> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetInstancedMaterialBindGroup<D, 1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<D, 3>,
+    DrawMeshInstanced<D>,
+);
 
-impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
-    type Param = (SRes<RenderAssets<Mesh>>, SRes<RenderMeshInstances>);
+/// World space bounding sphere of an instance entity, used to pick its level of detail.
+#[derive(Component)]
+struct InstanceBounds {
+    center: Vec3,
+    radius: f32,
+}
+
+/// The [`InstanceData`] of every instance entity, kept in the render world across frames so that
+/// it is only copied and uploaded again when it changes.
+#[derive(Resource)]
+struct RenderInstances<D: InstancedMaterial> {
+    instances: HashMap<Entity, RenderInstance<D>>,
+}
+
+impl<D: InstancedMaterial> Default for RenderInstances<D> {
+    fn default() -> Self {
+        Self {
+            instances: HashMap::new(),
+        }
+    }
+}
+
+struct RenderInstance<D: InstancedMaterial> {
+    instance_data: InstanceData<D>,
+    /// Instances that changed since they were last written to `buffer`, or `None` if nothing
+    /// changed.
+    changed: Option<Range<usize>>,
+    /// `None` until the instances are first uploaded, and while there are none.
+    buffer: Option<InstanceBuffer>,
+}
+
+/// The mesh and number of instances each instance entity is drawn with, per camera or light view.
+#[derive(Resource)]
+struct InstanceLodSelections<D> {
+    selections: HashMap<(Entity, Entity), InstanceLodSelection>,
+    marker: PhantomData<D>,
+}
+
+impl<D> Default for InstanceLodSelections<D> {
+    fn default() -> Self {
+        Self {
+            selections: HashMap::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+struct InstanceLodSelection {
+    mesh: AssetId<Mesh>,
+    /// View space depth of the entity, which its phase items are sorted by.
+    depth: f32,
+    /// Number of instances at the start of the instance buffer that are drawn, or that are culled
+    /// by [`prepare_culled_instances`].
+    instance_count: u32,
+    culled: Option<CulledInstances>,
+}
+
+/// Instances culled one by one for a view, with [`InstanceCulling::Instance`].
+#[derive(Clone)]
+struct CulledInstances {
+    buffer: Buffer,
+    indices: Buffer,
+    /// Indirect draw arguments written by the culling pass, or `None` if the instances were
+    /// culled on the CPU and their number is the `instance_count` of the selection.
+    indirect: Option<Buffer>,
+}
+
+/// The buffers the instances of each entity are culled into for each view, by view and entity.
+/// They are kept across frames, and only replaced when more instances are culled than they hold,
+/// or when the instances switch between culling on the CPU and in the culling pass.
+#[derive(Resource)]
+struct CulledInstanceBuffers<D> {
+    buffers: HashMap<(Entity, Entity), CulledInstanceBuffer>,
+    marker: PhantomData<D>,
+}
+
+impl<D> Default for CulledInstanceBuffers<D> {
+    fn default() -> Self {
+        Self {
+            buffers: HashMap::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+struct CulledInstanceBuffer {
+    culled: CulledInstances,
+    /// Number of instances the buffers hold.
+    capacity: usize,
+    /// The resources of the culling pass, or `None` if the instances are culled on the CPU.
+    culling: Option<CullingBuffers>,
+}
+
+struct CullingBuffers {
+    uniform: Buffer,
+    /// The bind group of the culling pass, with the instance buffer it reads from.
+    bind_group: Option<(BufferId, BindGroup)>,
+}
+
+impl<D: InstancedMaterial> CulledInstanceBuffers<D> {
+    /// The buffers of the instances of `key`, replaced if they hold fewer than `count` instances
+    /// or were made for the other culling path. `gpu` tells whether the instances are culled in
+    /// the culling pass, which only becomes ready once its pipeline has compiled.
+    fn get_or_grow(
+        &mut self,
+        key: (Entity, Entity),
+        count: usize,
+        gpu: bool,
+        render_device: &RenderDevice,
+    ) -> &mut CulledInstanceBuffer {
+        let buffer = self
+            .buffers
+            .entry(key)
+            .or_insert_with(|| CulledInstanceBuffer::new::<D>(count, gpu, render_device));
+        if !culled_buffer_fits(buffer.capacity, buffer.culling.is_some(), count, gpu) {
+            *buffer = CulledInstanceBuffer::new::<D>(count, gpu, render_device);
+        }
+        buffer
+    }
+}
+
+/// Whether culled instance buffers that hold `capacity` instances, and were made for the culling
+/// pass if `made_for_gpu`, can take `count` instances culled on the GPU if `gpu`.
+fn culled_buffer_fits(capacity: usize, made_for_gpu: bool, count: usize, gpu: bool) -> bool {
+    count <= capacity && made_for_gpu == gpu
+}
+
+impl CulledInstanceBuffer {
+    fn new<D: InstancedMaterial>(count: usize, gpu: bool, render_device: &RenderDevice) -> Self {
+        let capacity = count.next_power_of_two();
+        // WebGL2 does not allow storage buffers, where instances are culled on the CPU instead.
+        let usage = if gpu {
+            BufferUsages::VERTEX | BufferUsages::STORAGE | BufferUsages::COPY_DST
+        } else {
+            BufferUsages::VERTEX | BufferUsages::COPY_DST
+        };
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("culled instance data buffer"),
+            size: (capacity * std::mem::size_of::<D>()) as u64,
+            usage,
+            mapped_at_creation: false,
+        });
+        let indices = render_device.create_buffer(&BufferDescriptor {
+            label: Some("culled instance index buffer"),
+            size: (capacity * std::mem::size_of::<u32>()) as u64,
+            usage,
+            mapped_at_creation: false,
+        });
+        let indirect = gpu.then(|| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some("culled instance indirect buffer"),
+                size: (5 * std::mem::size_of::<u32>()) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        });
+        let culling = gpu.then(|| CullingBuffers {
+            uniform: render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance culling buffer"),
+                size: std::mem::size_of::<CullingUniform>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            bind_group: None,
+        });
+        Self {
+            culled: CulledInstances {
+                buffer,
+                indices,
+                indirect,
+            },
+            capacity,
+            culling,
+        }
+    }
+}
+
+/// Copies [`InstanceData`] into the render world when it is added or changed, and drops the
+/// instances of entities that no longer have it.
+fn extract_instance_data<D: InstancedMaterial>(
+    mut render_instances: ResMut<RenderInstances<D>>,
+    query: Extract<Query<(Entity, Ref<InstanceData<D>>)>>,
+) {
+    for (entity, instance_data) in &query {
+        let Some(render_instance) = render_instances.instances.get_mut(&entity) else {
+            render_instances.instances.insert(
+                entity,
+                RenderInstance {
+                    instance_data: instance_data.clone(),
+                    changed: Some(0..instance_data.data.len()),
+                    buffer: None,
+                },
+            );
+            continue;
+        };
+        if !instance_data.is_changed() {
+            continue;
+        }
+        let old = &render_instance.instance_data.data;
+        render_instance.changed = Some(if old.len() == instance_data.data.len() {
+            changed_instances(old, &instance_data.data)
+        } else {
+            0..instance_data.data.len()
+        });
+        render_instance.instance_data = instance_data.clone();
+    }
+    render_instances
+        .instances
+        .retain(|entity, _| query.contains(*entity));
+}
+
+/// The range of instances that differ between `old` and `new`, which have the same length.
+fn changed_instances<D: Pod>(old: &[D], new: &[D]) -> Range<usize> {
+    let differs = |&i: &usize| bytemuck::bytes_of(&old[i]) != bytemuck::bytes_of(&new[i]);
+    let start = (0..new.len()).find(differs).unwrap_or(new.len());
+    let end = (start..new.len())
+        .rev()
+        .find(differs)
+        .map_or(start, |i| i + 1);
+    start..end
+}
+
+/// Registers instance entities as render mesh instances, so that the mesh uniform and mesh bind
+/// group are prepared for them like for any other mesh.
+#[allow(clippy::type_complexity)]
+fn extract_instanced_meshes<D: InstancedMaterial>(
+    mut commands: Commands,
+    mut render_mesh_instances: ResMut<RenderMeshInstances>,
+    query: Extract<
+        Query<(
+            Entity,
+            &ViewVisibility,
+            &GlobalTransform,
+            &InstanceData<D>,
+            Option<&Aabb>,
+            Has<NotShadowReceiver>,
+            Has<NotShadowCaster>,
+        )>,
+    >,
+) {
+    for (entity, view_visibility, transform, instance_data, aabb, not_receiver, not_caster) in
+        &query
+    {
+        if !view_visibility.get() {
+            continue;
+        }
+        let mut render_entity = commands.get_or_spawn(entity);
+        render_entity.insert((Mesh3d, *transform));
+        if let Some(aabb) = aabb {
+            render_entity.insert(InstanceBounds {
+                center: transform.transform_point(aabb.center.into()),
+                radius: transform.radius_vec3a(aabb.half_extents),
+            });
+        }
+
+        let transform = transform.affine();
+        let mut flags = if not_receiver {
+            MeshFlags::empty()
+        } else {
+            MeshFlags::SHADOW_RECEIVER
+        };
+        if transform.matrix3.determinant().is_sign_positive() {
+            flags |= MeshFlags::SIGN_DETERMINANT_MODEL_3X3;
+        }
+        render_mesh_instances.insert(
+            entity,
+            RenderMeshInstance {
+                transforms: MeshTransforms {
+                    transform: (&transform).into(),
+                    previous_transform: (&transform).into(),
+                    flags: flags.bits(),
+                },
+                mesh_asset_id: instance_data.mesh.id(),
+                material_bind_group_id: MaterialBindGroupId::default(),
+                shadow_caster: !not_caster,
+                automatic_batching: false,
+            },
+        );
+    }
+}
+
+/// Sets the bind group of the [`InstanceData`] material at the configured `I` index.
+struct SetInstancedMaterialBindGroup<D, const I: usize>(PhantomData<D>);
+
+impl<P: PhaseItem, D: InstancedMaterial, const I: usize> RenderCommand<P>
+    for SetInstancedMaterialBindGroup<D, I>
+{
+    type Param = (SRes<RenderMaterials<D::M>>, SRes<RenderInstances<D>>);
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        _entity: (),
+        (materials, render_instances): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(render_instance) = render_instances.into_inner().instances.get(&item.entity())
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let material_id = render_instance.instance_data.material.id();
+        let Some(material) = materials.into_inner().get(&material_id) else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Sets the bind group of the level of detail parameters and transform of an instance entity at
+/// the configured `I` index.
+struct SetInstanceBindGroup<D, const I: usize>(PhantomData<D>);
+
+impl<P: PhaseItem, D: InstancedMaterial, const I: usize> RenderCommand<P>
+    for SetInstanceBindGroup<D, I>
+{
+    type Param = SRes<RenderInstances<D>>;
     type ViewWorldQuery = ();
-    type ItemWorldQuery = Read<InstanceBuffer>;
+    type ItemWorldQuery = ();
 
     #[inline]
     fn render<'w>(
         item: &P,
         _view: (),
-        instance_buffer: &'w InstanceBuffer,
-        (meshes, render_mesh_instances): SystemParamItem<'w, '_, Self::Param>,
+        _entity: (),
+        render_instances: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(instance_buffer) = render_instances
+            .into_inner()
+            .instances
+            .get(&item.entity())
+            .and_then(|render_instance| render_instance.buffer.as_ref())
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let Some(bind_group) = &instance_buffer.bind_group else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Draws the mesh and instances selected for the level of detail of the view, or the instances
+/// culled for the view.
+struct DrawMeshInstanced<D>(PhantomData<D>);
+
+impl<P: PhaseItem, D: InstancedMaterial> RenderCommand<P> for DrawMeshInstanced<D> {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<InstanceLodSelections<D>>,
+        SRes<RenderInstances<D>>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view: Entity,
+        _entity: (),
+        (meshes, lod_selections, render_instances): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let Some(mesh_instance) = render_mesh_instances.get(&item.entity()) else {
+        let Some(instance_buffer) = render_instances
+            .into_inner()
+            .instances
+            .get(&item.entity())
+            .and_then(|render_instance| render_instance.buffer.as_ref())
+        else {
             return RenderCommandResult::Failure;
         };
-        let gpu_mesh = match meshes.into_inner().get(mesh_instance.mesh_asset_id) {
+        let Some(selection) = lod_selections
+            .into_inner()
+            .selections
+            .get(&(view, item.entity()))
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let gpu_mesh = match meshes.into_inner().get(selection.mesh) {
             Some(gpu_mesh) => gpu_mesh,
             None => return RenderCommandResult::Failure,
         };
 
+        if selection.instance_count == 0 {
+            return RenderCommandResult::Success;
+        }
+
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+        let indirect = match &selection.culled {
+            Some(culled) => {
+                pass.set_vertex_buffer(1, culled.buffer.slice(..));
+                pass.set_vertex_buffer(2, culled.indices.slice(..));
+                culled.indirect.as_ref()
+            }
+            None => {
+                pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+                pass.set_vertex_buffer(2, instance_buffer.indices.slice(..));
+                None
+            }
+        };
 
         match &gpu_mesh.buffer_info {
             GpuBufferInfo::Indexed {
@@ -128,11 +816,15 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
                 count,
             } => {
                 pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
-            }
-            GpuBufferInfo::NonIndexed => {
-                pass.draw(0..gpu_mesh.vertex_count, 0..instance_buffer.length as u32);
+                match indirect {
+                    Some(indirect) => pass.draw_indexed_indirect(indirect, 0),
+                    None => pass.draw_indexed(0..*count, 0, 0..selection.instance_count),
+                }
             }
+            GpuBufferInfo::NonIndexed => match indirect {
+                Some(indirect) => pass.draw_indirect(indirect, 0),
+                None => pass.draw(0..gpu_mesh.vertex_count, 0..selection.instance_count),
+            },
         }
         RenderCommandResult::Success
     }
@@ -142,11 +834,13 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
 struct InstancingPipeline<D> {
     mesh_pipeline: MeshPipeline,
     material_layout: BindGroupLayout,
+    instance_layout: BindGroupLayout,
     shader: Handle<Shader>,
+    _instancing_shader: Handle<Shader>,
     marker: PhantomData<D>,
 }
 
-impl<D> SpecializedMeshPipeline for InstancingPipeline<D> {
+impl<D: InstancedMaterial> SpecializedMeshPipeline for InstancingPipeline<D> {
     type Key = MeshPipelineKey;
 
     fn specialize(
@@ -158,23 +852,53 @@ impl<D> SpecializedMeshPipeline for InstancingPipeline<D> {
 
         descriptor.vertex.shader_defs.push("VERTEX_COLORS".into());
         descriptor.vertex.shader = self.shader.clone();
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: std::mem::size_of::<D>() as u64,
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![VertexAttribute {
-                format: VertexFormat::Float32x4,
-                offset: 0,
-                shader_location: 3, // shader locations 0-2 are taken up by Position, Normal and UV attributes
-            }],
-        });
+        descriptor
+            .vertex
+            .buffers
+            .extend(instance_buffer_layouts::<D>());
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
         descriptor.fragment.as_mut().unwrap().shader_defs = descriptor.vertex.shader_defs.clone();
 
         descriptor.layout.insert(1, self.material_layout.clone());
+        descriptor.layout.push(self.instance_layout.clone());
         Ok(descriptor)
     }
 }
 
+/// The layouts of the instance data and instance index buffers.
+fn instance_buffer_layouts<D: InstancedMaterial>() -> [VertexBufferLayout; 2] {
+    let attributes = D::vertex_attributes();
+    for attribute in &attributes {
+        assert!(
+            attribute.offset + attribute.format.size() <= std::mem::size_of::<D>() as u64,
+            "instance attribute at location {} reads past the end of an instance",
+            attribute.shader_location
+        );
+        assert!(
+            attribute.shader_location >= FIRST_INSTANCE_LOCATION,
+            "instance attribute at location {}, where it clashes with the attributes of the mesh \
+             or the instance index, rather than at `FIRST_INSTANCE_LOCATION` or past it",
+            attribute.shader_location
+        );
+    }
+    [
+        VertexBufferLayout {
+            array_stride: std::mem::size_of::<D>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes,
+        },
+        VertexBufferLayout {
+            array_stride: VertexFormat::Uint32.size(),
+            step_mode: VertexStepMode::Instance,
+            attributes: vec![VertexAttribute {
+                format: VertexFormat::Uint32,
+                offset: 0,
+                shader_location: INSTANCE_INDEX_LOCATION,
+            }],
+        },
+    ]
+}
+
 impl<D: InstancedMaterial> FromWorld for InstancingPipeline<D> {
     fn from_world(world: &mut World) -> Self {
         let asset_server = world.resource::<AssetServer>();
@@ -185,83 +909,853 @@ impl<D: InstancedMaterial> FromWorld for InstancingPipeline<D> {
 
         Self {
             shader,
+            _instancing_shader: asset_server.load("shaders/instancing.wgsl"),
             mesh_pipeline: mesh_pipeline.clone(),
             material_layout: D::material_bind_group_layout::<D::M>(render_device),
+            instance_layout: instance_bind_group_layout::<D>(render_device),
             marker: PhantomData,
         }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn queue_custom<D: 'static>(
+fn instance_bind_group_layout<D: InstancedMaterial>(
+    render_device: &RenderDevice,
+) -> BindGroupLayout {
+    let mut entries = vec![
+        BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+        BindGroupLayoutEntry {
+            binding: 1,
+            visibility: ShaderStages::VERTEX_FRAGMENT,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        },
+    ];
+    entries.extend(D::shared_layout_entries());
+    entries.extend(D::entity_layout_entries());
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("instance layout"),
+        entries: &entries,
+    })
+}
+
+/// Draws instances into the prepasses and shadow maps, on top of the material's own prepass
+/// pipeline.
+#[derive(Resource)]
+struct InstancingPrepassPipeline<D: InstancedMaterial> {
+    prepass_pipeline: PrepassPipeline<D::M>,
+    instance_layout: BindGroupLayout,
+    shader: Handle<Shader>,
+    _instancing_shader: Handle<Shader>,
+}
+
+impl<D: InstancedMaterial> SpecializedMeshPipeline for InstancingPrepassPipeline<D>
+where
+    <D::M as AsBindGroup>::Data: PartialEq + Eq + Hash + Clone,
+{
+    /// The key of the material's prepass pipeline, and whether instances are drawn into a shadow
+    /// map.
+    type Key = (MaterialPipelineKey<D::M>, bool);
+
+    fn specialize(
+        &self,
+        (key, shadow): Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.prepass_pipeline.specialize(key, layout)?;
+
+        descriptor.vertex.shader = self.shader.clone();
+        // The prepass binds the tangent of the mesh at location 3 for the normal prepass, and its
+        // joints and color past it, which instance attributes stay clear of by starting at
+        // `FIRST_INSTANCE_LOCATION`.
+        descriptor
+            .vertex
+            .buffers
+            .extend(instance_buffer_layouts::<D>());
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment.shader = self.shader.clone();
+        }
+        if shadow {
+            descriptor
+                .vertex
+                .shader_defs
+                .push("INSTANCE_SHADOW_PASS".into());
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment.shader_defs.push("INSTANCE_SHADOW_PASS".into());
+            }
+        }
+
+        descriptor.layout.push(self.instance_layout.clone());
+        Ok(descriptor)
+    }
+}
+
+impl<D: InstancedMaterial> FromWorld for InstancingPrepassPipeline<D> {
+    fn from_world(world: &mut World) -> Self {
+        let prepass_pipeline = PrepassPipeline::from_world(world);
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            prepass_pipeline,
+            instance_layout: instance_bind_group_layout::<D>(render_device),
+            shader: asset_server
+                .load(D::prepass_shader_path().expect("prepass pipeline without a prepass shader")),
+            _instancing_shader: asset_server.load("shaders/instancing.wgsl"),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_custom<D>(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<InstancingPipeline<D>>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<InstancingPipeline<D>>>,
     pipeline_cache: Res<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
-    // render_mesh_instances: Res<RenderMeshInstances>,
-    material_meshes: Query<(Entity, &InstanceData<D>)>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    mut lod_selections: ResMut<InstanceLodSelections<D>>,
+    render_instances: Res<RenderInstances<D>>,
+    instance_bounds: Query<&InstanceBounds>,
+    mut views: Query<(
+        Entity,
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&mut RenderPhase<Opaque3d>>,
+        Option<&mut RenderPhase<Transparent3d>>,
+        (
+            Has<DepthPrepass>,
+            Has<NormalPrepass>,
+            Has<MotionVectorPrepass>,
+        ),
+    )>,
 ) where
-    D: InstancedMaterial,
+    D: InstancedMaterial + 'static,
 {
-    let draw_custom = transparent_3d_draw_functions
+    lod_selections.selections.clear();
+    let draw_opaque = opaque_3d_draw_functions.read().id::<DrawInstanced<D>>();
+    let draw_transparent = transparent_3d_draw_functions
         .read()
-        .id::<DrawInstanced<D::M>>();
+        .id::<DrawInstanced<D>>();
 
-    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+    let mut msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+    if D::phase() == InstancedPhase::Transparent {
+        msaa_key |= MeshPipelineKey::BLEND_ALPHA;
+    }
 
-    for (view, mut transparent_phase) in &mut views {
-        let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
-        // let rangefinder = view.rangefinder3d();
-        for (entity, instance_data) in &material_meshes {
-            // let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
-            //     continue;
-            // };
-            let Some(mesh) = meshes.get(instance_data.mesh.id()) else {
+    for (view_entity, view, visible_entities, mut opaque_phase, mut transparent_phase, prepasses) in
+        &mut views
+    {
+        // The view bind group holds the textures of the view's prepasses.
+        let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr) | prepass_key(prepasses);
+        let view_position = view.transform.translation();
+        let rangefinder = view.rangefinder3d();
+        for &entity in visible_entities.iter() {
+            let Some(render_instance) = render_instances.instances.get(&entity) else {
                 continue;
             };
-            let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
-            let pipeline = pipelines
-                .specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout)
-                .unwrap();
-            transparent_phase.add(Transparent3d {
-                entity,
-                pipeline,
-                draw_function: draw_custom,
-                // distance: rangefinder
-                //     .distance_translation(&mesh_instance.transforms.transform.translation),
-                distance: 0.,
-                batch_range: 0..1,
-                dynamic_offset: None,
+            let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
+                continue;
+            };
+            let bounds = instance_bounds.get(entity).ok();
+            let (mesh_id, instance_count) = render_instance
+                .instance_data
+                .lod_selection(bounds, view_position);
+            if instance_count == 0 {
+                continue;
+            }
+            let Some(mesh) = meshes.get(mesh_id) else {
+                continue;
+            };
+            let center = bounds.map_or(mesh_instance.transforms.transform.translation, |bounds| {
+                bounds.center
             });
+            let depth = rangefinder.distance_translation(&center);
+            let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline =
+                match pipelines.specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+            lod_selections.selections.insert(
+                (view_entity, entity),
+                InstanceLodSelection {
+                    mesh: mesh_id,
+                    depth,
+                    instance_count,
+                    culled: None,
+                },
+            );
+            match (
+                D::phase(),
+                opaque_phase.as_mut(),
+                transparent_phase.as_mut(),
+            ) {
+                (InstancedPhase::Opaque, Some(opaque_phase), _) => opaque_phase.add(Opaque3d {
+                    entity,
+                    pipeline,
+                    draw_function: draw_opaque,
+                    distance: depth,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                }),
+                (InstancedPhase::Transparent, _, Some(transparent_phase)) => {
+                    transparent_phase.add(Transparent3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_transparent,
+                        distance: depth,
+                        batch_range: 0..1,
+                        dynamic_offset: None,
+                    });
+                }
+                _ => {}
+            }
         }
     }
 }
 
-#[derive(Component)]
-struct InstanceBuffer {
-    buffer: Buffer,
-    length: usize,
+/// The pipeline key bits of the prepasses a view has.
+fn prepass_key((depth, normal, motion_vector): (bool, bool, bool)) -> MeshPipelineKey {
+    let mut key = MeshPipelineKey::NONE;
+    if depth {
+        key |= MeshPipelineKey::DEPTH_PREPASS;
+    }
+    if normal {
+        key |= MeshPipelineKey::NORMAL_PREPASS;
+    }
+    if motion_vector {
+        key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
+    }
+    key
 }
 
-fn prepare_instance_buffers<D: 'static>(
-    mut commands: Commands,
-    query: Query<(Entity, &InstanceData<D>)>,
-    render_device: Res<RenderDevice>,
+/// Queues the instance entities selected by [`queue_custom`] into the depth, normal and motion
+/// vector prepasses of the views that have them.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_prepass<D>(
+    draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    prepass_pipeline: Res<InstancingPrepassPipeline<D>>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancingPrepassPipeline<D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    materials: Res<RenderMaterials<D::M>>,
+    lod_selections: Res<InstanceLodSelections<D>>,
+    render_instances: Res<RenderInstances<D>>,
+    mut views: Query<(
+        &mut RenderPhase<Opaque3dPrepass>,
+        (
+            Has<DepthPrepass>,
+            Has<NormalPrepass>,
+            Has<MotionVectorPrepass>,
+        ),
+    )>,
 ) where
-    D: InstancedMaterial,
+    D: InstancedMaterial + 'static,
+    <D::M as AsBindGroup>::Data: PartialEq + Eq + Hash + Clone,
 {
-    for (entity, instance_data) in &query {
+    // Like Bevy's own materials, only opaque instances are drawn into the prepasses.
+    if D::phase() != InstancedPhase::Opaque {
+        return;
+    }
+    let draw_prepass = draw_functions.read().id::<DrawInstancedPrepass<D>>();
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (&(view_entity, entity), selection) in &lod_selections.selections {
+        let Ok((mut prepass_phase, prepasses)) = views.get_mut(view_entity) else {
+            continue;
+        };
+        let (Some(render_instance), Some(mesh)) = (
+            render_instances.instances.get(&entity),
+            meshes.get(selection.mesh),
+        ) else {
+            continue;
+        };
+        let Some(material) = materials.get(&render_instance.instance_data.material.id()) else {
+            continue;
+        };
+
+        let mesh_key = msaa_key
+            | prepass_key(prepasses)
+            | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+        let key = MaterialPipelineKey {
+            mesh_key,
+            bind_group_data: material.key.clone(),
+        };
+        let pipeline_id = match pipelines.specialize(
+            &pipeline_cache,
+            &prepass_pipeline,
+            (key, false),
+            &mesh.layout,
+        ) {
+            Ok(pipeline_id) => pipeline_id,
+            Err(err) => {
+                error!("{}", err);
+                continue;
+            }
+        };
+        prepass_phase.add(Opaque3dPrepass {
+            entity,
+            pipeline_id,
+            draw_function: draw_prepass,
+            distance: selection.depth,
+            batch_range: 0..1,
+            dynamic_offset: None,
+        });
+    }
+}
+
+/// Queues instance entities that cast shadows into the shadow maps of the lights of each view.
+/// Entities are drawn with the level of detail they have as seen from the view's camera, so their
+/// shadows thin out along with them.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_shadows<D>(
+    draw_functions: Res<DrawFunctions<Shadow>>,
+    prepass_pipeline: Res<InstancingPrepassPipeline<D>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancingPrepassPipeline<D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    materials: Res<RenderMaterials<D::M>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    mut lod_selections: ResMut<InstanceLodSelections<D>>,
+    render_instances: Res<RenderInstances<D>>,
+    instance_bounds: Query<&InstanceBounds>,
+    views: Query<(Entity, &ExtractedView, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    point_light_entities: Query<&CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities: Query<&CascadesVisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_light_entities: Query<&VisibleEntities, With<ExtractedPointLight>>,
+) where
+    D: InstancedMaterial + 'static,
+    <D::M as AsBindGroup>::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_shadow = draw_functions.read().id::<DrawInstancedPrepass<D>>();
+
+    for (view_entity, view, view_lights) in &views {
+        let view_position = view.transform.translation();
+        for &view_light_entity in &view_lights.lights {
+            let Ok((light_entity, mut shadow_phase)) =
+                view_light_shadow_phases.get_mut(view_light_entity)
+            else {
+                continue;
+            };
+            let visible_entities = match light_entity {
+                LightEntity::Directional {
+                    light_entity,
+                    cascade_index,
+                } => directional_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .and_then(|cascades| cascades.entities.get(&view_entity))
+                    .and_then(|cascades| cascades.get(*cascade_index)),
+                LightEntity::Point {
+                    light_entity,
+                    face_index,
+                } => point_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .map(|cubemap| cubemap.get(*face_index)),
+                LightEntity::Spot { light_entity } => spot_light_entities.get(*light_entity).ok(),
+            };
+            let Some(visible_entities) = visible_entities else {
+                continue;
+            };
+            let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
+
+            for &entity in visible_entities.iter() {
+                let Some(render_instance) = render_instances.instances.get(&entity) else {
+                    continue;
+                };
+                if !render_mesh_instances
+                    .get(&entity)
+                    .is_some_and(|mesh_instance| mesh_instance.shadow_caster)
+                {
+                    continue;
+                }
+                let instance_data = &render_instance.instance_data;
+                let (mesh_id, instance_count) =
+                    instance_data.lod_selection(instance_bounds.get(entity).ok(), view_position);
+                if instance_count == 0 {
+                    continue;
+                }
+                let (Some(mesh), Some(material)) = (
+                    meshes.get(mesh_id),
+                    materials.get(&instance_data.material.id()),
+                ) else {
+                    continue;
+                };
+
+                let mut mesh_key =
+                    MeshPipelineKey::from_primitive_topology(mesh.primitive_topology)
+                        | MeshPipelineKey::DEPTH_PREPASS;
+                if is_directional_light {
+                    mesh_key |= MeshPipelineKey::DEPTH_CLAMP_ORTHO;
+                }
+                mesh_key |= match material.properties.alpha_mode {
+                    AlphaMode::Mask(_)
+                    | AlphaMode::Blend
+                    | AlphaMode::Premultiplied
+                    | AlphaMode::Add => MeshPipelineKey::MAY_DISCARD,
+                    _ => MeshPipelineKey::NONE,
+                };
+                let key = MaterialPipelineKey {
+                    mesh_key,
+                    bind_group_data: material.key.clone(),
+                };
+                let pipeline = match pipelines.specialize(
+                    &pipeline_cache,
+                    &prepass_pipeline,
+                    (key, true),
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+                lod_selections.selections.insert(
+                    (view_light_entity, entity),
+                    InstanceLodSelection {
+                        mesh: mesh_id,
+                        depth: 0.,
+                        instance_count,
+                        culled: None,
+                    },
+                );
+                shadow_phase.add(Shadow {
+                    distance: 0.,
+                    entity,
+                    pipeline,
+                    draw_function: draw_shadow,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+        }
+    }
+}
+
+struct InstanceBuffer {
+    buffer: Buffer,
+    /// The index of each instance, read by the instancing shaders to fade out instances.
+    indices: Buffer,
+    lod_buffer: Buffer,
+    transform_buffer: Buffer,
+    /// The transform last written to `transform_buffer`, or `None` until it is first written.
+    transform: Option<InstanceTransformUniform>,
+    /// `None` until it is created by [`prepare_instance_bind_groups`].
+    bind_group: Option<BindGroup>,
+    /// Number of instances in `buffer`.
+    len: usize,
+}
+
+/// The level of detail parameters of an instance entity, as read by `lod_density` in the
+/// instancing shaders.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct InstanceLodUniform {
+    distances: Vec4,
+    densities: Vec4,
+    levels: u32,
+    instance_count: u32,
+    _padding: [u32; 2],
+}
+
+impl InstanceLodUniform {
+    fn new<D: InstancedMaterial>(instance_data: &InstanceData<D>) -> Self {
+        let mut uniform = Self::zeroed();
+        for (i, lod) in instance_data.lods().iter().enumerate() {
+            uniform.distances[i] = lod.distance;
+            uniform.densities[i] = lod.density;
+        }
+        uniform.levels = instance_data.lods().len() as u32;
+        uniform.instance_count = instance_data.data.len() as u32;
+        uniform
+    }
+}
+
+/// The transform of an instance entity, as read by the instancing shaders. Instances are given in
+/// the local space of their entity.
+///
+/// The mesh uniform of an entity is indexed by the instance index of its draws, which instanced
+/// draws take over, so instance entities carry their own copy of its transform.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct InstanceTransformUniform {
+    world_from_local: Mat4,
+    /// The transform the entity had the frame before, for motion vectors.
+    previous_world_from_local: Mat4,
+    /// The `MeshFlags` of the entity.
+    flags: u32,
+    _padding: [u32; 3],
+}
+
+impl InstanceBuffer {
+    fn new<D: InstancedMaterial>(
+        instance_data: &InstanceData<D>,
+        render_device: &RenderDevice,
+    ) -> Self {
         let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("instance data buffer"),
             contents: bytemuck::cast_slice(instance_data.data.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            usage: BufferUsages::VERTEX | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+        let indices: Vec<u32> = (0..instance_data.data.len() as u32).collect();
+        let indices = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("instance index buffer"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: BufferUsages::VERTEX,
         });
-        commands.entity(entity).insert(InstanceBuffer {
+        let lod_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("instance lod buffer"),
+            contents: bytemuck::bytes_of(&InstanceLodUniform::new(instance_data)),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        let transform_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instance transform buffer"),
+            size: std::mem::size_of::<InstanceTransformUniform>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
             buffer,
-            length: instance_data.data.len(),
+            indices,
+            lod_buffer,
+            transform_buffer,
+            transform: None,
+            bind_group: None,
+            len: instance_data.data.len(),
+        }
+    }
+}
+
+/// Uploads the instances of entities whose [`InstanceData`] changed. Buffers are kept while the
+/// number of instances stays the same, and only the changed instances are written to them.
+fn prepare_instance_buffers<D>(
+    mut render_instances: ResMut<RenderInstances<D>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) where
+    D: InstancedMaterial + 'static,
+{
+    for render_instance in render_instances.instances.values_mut() {
+        let Some(changed) = render_instance.changed.take() else {
+            continue;
+        };
+        let instance_data = &render_instance.instance_data;
+        let reusable = render_instance
+            .buffer
+            .as_ref()
+            .filter(|buffer| buffer.len == instance_data.data.len());
+        if let Some(buffer) = reusable {
+            if !changed.is_empty() {
+                render_queue.write_buffer(
+                    &buffer.buffer,
+                    (changed.start * std::mem::size_of::<D>()) as u64,
+                    bytemuck::cast_slice(&instance_data.data[changed]),
+                );
+            }
+            render_queue.write_buffer(
+                &buffer.lod_buffer,
+                0,
+                bytemuck::bytes_of(&InstanceLodUniform::new(instance_data)),
+            );
+        } else {
+            render_instance.buffer = (!instance_data.data.is_empty())
+                .then(|| InstanceBuffer::new(instance_data, &render_device));
+        }
+    }
+}
+
+/// Creates the bind groups of new instance buffers, and of every instance buffer when the
+/// [`SharedInstanceBindings`] or [`EntityInstanceBindings`] change.
+fn prepare_instance_bind_groups<D>(
+    mut render_instances: ResMut<RenderInstances<D>>,
+    pipeline: Res<InstancingPipeline<D>>,
+    shared: Option<Res<SharedInstanceBindings<D>>>,
+    entity_bindings: Res<EntityInstanceBindings<D>>,
+    render_device: Res<RenderDevice>,
+) where
+    D: InstancedMaterial + 'static,
+{
+    let shared_entries = D::shared_layout_entries();
+    let shared_resources = match &shared {
+        Some(shared) => &shared.resources[..],
+        None if shared_entries.is_empty() => &[],
+        None => return,
+    };
+    let changed =
+        shared.as_ref().is_some_and(|shared| shared.is_changed()) || entity_bindings.is_changed();
+    let entity_entries = D::entity_layout_entries();
+
+    for (entity, buffer) in render_instances
+        .instances
+        .iter_mut()
+        .filter_map(|(entity, render_instance)| Some((entity, render_instance.buffer.as_mut()?)))
+    {
+        if buffer.bind_group.is_some() && !changed {
+            continue;
+        }
+        let entity_resources = match entity_bindings.bindings.get(entity) {
+            Some(resources) => &resources[..],
+            None if entity_entries.is_empty() => &[],
+            None => {
+                buffer.bind_group = None;
+                continue;
+            }
+        };
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: buffer.lod_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: buffer.transform_buffer.as_entire_binding(),
+            },
+        ];
+        entries.extend(
+            shared_entries
+                .iter()
+                .zip(shared_resources)
+                .chain(entity_entries.iter().zip(entity_resources))
+                .map(|(entry, resource)| BindGroupEntry {
+                    binding: entry.binding,
+                    resource: resource.get_binding(),
+                }),
+        );
+        buffer.bind_group = Some(render_device.create_bind_group(
+            "instance bind group",
+            &pipeline.instance_layout,
+            &entries,
+        ));
+    }
+}
+
+/// Uploads the transforms of instance entities that moved since they were last uploaded.
+fn prepare_instance_transforms<D>(
+    mut render_instances: ResMut<RenderInstances<D>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    transforms: Query<&GlobalTransform>,
+    render_queue: Res<RenderQueue>,
+) where
+    D: InstancedMaterial + 'static,
+{
+    for (entity, render_instance) in &mut render_instances.instances {
+        let (Some(buffer), Ok(transform), Some(mesh_instance)) = (
+            render_instance.buffer.as_mut(),
+            transforms.get(*entity),
+            render_mesh_instances.get(entity),
+        ) else {
+            continue;
+        };
+        let world_from_local = transform.compute_matrix();
+        let uniform = InstanceTransformUniform {
+            world_from_local,
+            previous_world_from_local: buffer
+                .transform
+                .map_or(world_from_local, |previous| previous.world_from_local),
+            flags: mesh_instance.transforms.flags,
+            _padding: [0; 3],
+        };
+        if buffer
+            .transform
+            .is_some_and(|previous| bytemuck::bytes_of(&previous) == bytemuck::bytes_of(&uniform))
+        {
+            continue;
+        }
+        render_queue.write_buffer(&buffer.transform_buffer, 0, bytemuck::bytes_of(&uniform));
+        buffer.transform = Some(uniform);
+    }
+}
+
+/// Culls the instances of every entity one by one for each view with
+/// [`InstanceCulling::Instance`]. Instances are culled in a compute pass where compute shaders
+/// are supported, and on the CPU otherwise.
+#[allow(clippy::too_many_arguments)]
+fn prepare_culled_instances<D>(
+    culling: Res<InstanceCulling>,
+    culling_pipeline: Res<InstanceCullingPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    meshes: Res<RenderAssets<Mesh>>,
+    mut lod_selections: ResMut<InstanceLodSelections<D>>,
+    mut culled_buffers: ResMut<CulledInstanceBuffers<D>>,
+    render_instances: Res<RenderInstances<D>>,
+    views: Query<(&ExtractedView, &Frustum)>,
+    transforms: Query<&GlobalTransform>,
+) where
+    D: InstancedMaterial + 'static,
+{
+    if *culling != InstanceCulling::Instance {
+        culled_buffers.buffers.clear();
+        return;
+    }
+    let compute_pipeline = culling_pipeline.get(&pipeline_cache);
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("instance culling encoder"),
+    });
+    let selections = &mut lod_selections.selections;
+    culled_buffers
+        .buffers
+        .retain(|key, _| selections.contains_key(key));
+
+    for (&(view_entity, entity), selection) in selections.iter_mut() {
+        // Light views have no frustum, so shadow maps are drawn without culling single instances.
+        let (Ok((view, frustum)), Ok(transform), Some(render_instance)) = (
+            views.get(view_entity),
+            transforms.get(entity),
+            render_instances.instances.get(&entity),
+        ) else {
+            continue;
+        };
+        let Some(instance_buffer) = &render_instance.buffer else {
+            continue;
+        };
+        let instance_data = &render_instance.instance_data;
+        let view = CullingView {
+            frustum: *frustum,
+            position: view.transform.translation(),
+        };
+        let culled_count = selection.instance_count as usize;
+        let key = (view_entity, entity);
+
+        let Some(compute_pipeline) = compute_pipeline else {
+            let positions: Vec<Vec4> = instance_data.data.iter().map(position_scale).collect();
+            let indices = cull_instances(
+                &view,
+                transform,
+                instance_data.instance_radius,
+                &positions,
+                |distance| instance_data.lod_keep_fraction(distance),
+            );
+            selection.instance_count = indices.len() as u32;
+            if indices.is_empty() {
+                continue;
+            }
+            let culled: Vec<D> = indices
+                .iter()
+                .map(|index| instance_data.data[*index as usize])
+                .collect();
+            let culled_buffer =
+                culled_buffers.get_or_grow(key, indices.len(), false, &render_device);
+            render_queue.write_buffer(
+                &culled_buffer.culled.buffer,
+                0,
+                bytemuck::cast_slice(&culled),
+            );
+            render_queue.write_buffer(
+                &culled_buffer.culled.indices,
+                0,
+                bytemuck::cast_slice(&indices),
+            );
+            selection.culled = Some(culled_buffer.culled.clone());
+            continue;
+        };
+
+        let Some(gpu_mesh) = meshes.get(selection.mesh) else {
+            continue;
+        };
+        if culled_count == 0 {
+            continue;
+        }
+        let vertex_or_index_count = match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed { count, .. } => *count,
+            GpuBufferInfo::NonIndexed => gpu_mesh.vertex_count,
+        };
+        let culled_buffer = culled_buffers.get_or_grow(key, culled_count, true, &render_device);
+        let planes = std::array::from_fn(|i| frustum.half_spaces[i].normal_d());
+        let uniform = CullingUniform {
+            world_from_local: transform.compute_matrix(),
+            planes,
+            view_position: view.position,
+            instance_radius: instance_data.instance_radius,
+            lod: InstanceLodUniform::new(instance_data),
+            culled_count: culled_count as u32,
+            instance_stride: (std::mem::size_of::<D>() / 4) as u32,
+            _padding: [0; 2],
+        };
+        let (Some(culling), Some(indirect)) =
+            (&mut culled_buffer.culling, &culled_buffer.culled.indirect)
+        else {
+            continue;
+        };
+        render_queue.write_buffer(&culling.uniform, 0, bytemuck::bytes_of(&uniform));
+        // Indexed and non-indexed draws both take the instance count second, which the culling
+        // pass counts up from zero.
+        render_queue.write_buffer(
+            indirect,
+            0,
+            bytemuck::cast_slice(&[vertex_or_index_count, 0, 0, 0, 0]),
+        );
+        // The instance buffer is replaced when its entity gains or loses instances.
+        if !culling
+            .bind_group
+            .as_ref()
+            .is_some_and(|(buffer, _)| *buffer == instance_buffer.buffer.id())
+        {
+            let bind_group = render_device.create_bind_group(
+                "instance culling bind group",
+                &culling_pipeline.layout,
+                &BindGroupEntries::sequential((
+                    culling.uniform.as_entire_binding(),
+                    instance_buffer.buffer.as_entire_binding(),
+                    culled_buffer.culled.buffer.as_entire_binding(),
+                    culled_buffer.culled.indices.as_entire_binding(),
+                    indirect.as_entire_binding(),
+                )),
+            );
+            culling.bind_group = Some((instance_buffer.buffer.id(), bind_group));
+        }
+        let Some((_, bind_group)) = &culling.bind_group else {
+            continue;
+        };
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("instance culling pass"),
         });
+        pass.set_pipeline(compute_pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.dispatch_workgroups((culled_count as u32).div_ceil(CULLING_WORKGROUP_SIZE), 1, 1);
+        drop(pass);
+        selection.culled = Some(culled_buffer.culled.clone());
+    }
+
+    render_queue.submit([encoder.finish()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::culled_buffer_fits;
+
+    #[test]
+    fn culled_buffers_follow_the_culling_path() {
+        // Buffers made while the culling pipeline compiles lack the indirect and uniform buffers
+        // of the culling pass, so they are replaced once it is ready, and the other way around.
+        assert!(culled_buffer_fits(16, false, 10, false));
+        assert!(!culled_buffer_fits(16, false, 10, true));
+        assert!(culled_buffer_fits(16, true, 10, true));
+        assert!(!culled_buffer_fits(16, true, 10, false));
+        assert!(!culled_buffer_fits(16, true, 17, true));
     }
 }
@@ -1 +1,6 @@
+pub mod culling;
+pub mod displacement;
+pub mod flatten;
+pub mod grass;
 pub mod instancing;
+pub mod wind;
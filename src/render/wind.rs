@@ -0,0 +1,204 @@
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_resource::{
+    AddressMode, Buffer, BufferInitDescriptor, BufferUsages, FilterMode, OwnedBindingResource,
+    Sampler, SamplerDescriptor, TextureView,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::texture::FallbackImage;
+use bevy::render::Extract;
+use bytemuck::{Pod, Zeroable};
+
+use crate::wind::{GrassWind, GrassWindZone, WindZoneKind, MAX_WIND_ZONES};
+
+/// The [`GrassWind`], as read by `wind_offset` in `wind.wgsl`.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GrassWindUniform {
+    direction: Vec2,
+    strength: f32,
+    gust_frequency: f32,
+    turbulence_scale: f32,
+    noise_scale: f32,
+    scroll_speed: f32,
+    _padding: u32,
+}
+
+impl GrassWindUniform {
+    fn new(wind: &GrassWind) -> Self {
+        // Without noise, the white fallback texture is bound, at any scale.
+        let (noise_scale, scroll_speed) = wind
+            .noise
+            .as_ref()
+            .map_or((1., 0.), |noise| (noise.scale, noise.scroll_speed));
+        Self {
+            direction: wind.direction.normalize_or_zero(),
+            strength: wind.strength,
+            gust_frequency: wind.gust_frequency,
+            turbulence_scale: wind.turbulence_scale,
+            noise_scale,
+            scroll_speed,
+            _padding: 0,
+        }
+    }
+}
+
+/// A [`GrassWindZone`], as read by `wind_zone_offset` in `wind.wgsl`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+struct WindZoneUniform {
+    position: Vec3,
+    /// `0` for radial, `1` for directional and `2` for vortex zones.
+    kind: u32,
+    /// The direction of directional zones, on the XZ plane.
+    direction: Vec2,
+    radius: f32,
+    strength: f32,
+    falloff: f32,
+    _padding: [u32; 3],
+}
+
+/// The [`GrassWindZone`]s that sway grass this frame.
+#[derive(Clone, Copy, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+struct WindZonesUniform {
+    count: u32,
+    _padding: [u32; 3],
+    zones: [WindZoneUniform; MAX_WIND_ZONES],
+}
+
+/// The strongest [`GrassWindZone`]s of the main world, strongest first.
+#[derive(Resource, Default)]
+pub struct ExtractedWindZones {
+    zones: Vec<WindZoneUniform>,
+}
+
+/// The resources grass reads the [`GrassWind`] from, shared by the grass of all materials.
+#[derive(Resource)]
+pub struct GrassWindBindings {
+    buffer: Buffer,
+    zone_buffer: Buffer,
+    /// The noise texture, or the white fallback image without one.
+    noise: TextureView,
+    /// Repeats the noise texture, whatever the sampler of its image.
+    sampler: Sampler,
+    _wind_shader: Handle<Shader>,
+}
+
+impl GrassWindBindings {
+    /// The resources, in the order of the layout entries of
+    /// [`Grass`](crate::grass::Grass).
+    pub fn resources(&self) -> Vec<OwnedBindingResource> {
+        vec![
+            OwnedBindingResource::Buffer(self.buffer.clone()),
+            OwnedBindingResource::TextureView(self.noise.clone()),
+            OwnedBindingResource::Sampler(self.sampler.clone()),
+            OwnedBindingResource::Buffer(self.zone_buffer.clone()),
+        ]
+    }
+}
+
+impl FromWorld for GrassWindBindings {
+    fn from_world(world: &mut World) -> Self {
+        let wind = world.resource::<GrassWind>();
+        let render_device = world.resource::<RenderDevice>();
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("grass wind buffer"),
+            contents: bytemuck::bytes_of(&GrassWindUniform::new(wind)),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        let zone_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("grass wind zone buffer"),
+            contents: bytemuck::bytes_of(&WindZonesUniform::zeroed()),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("grass wind noise sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+        Self {
+            buffer,
+            zone_buffer,
+            noise: world.resource::<FallbackImage>().d2.texture_view.clone(),
+            sampler,
+            _wind_shader: world.resource::<AssetServer>().load("shaders/wind.wgsl"),
+        }
+    }
+}
+
+pub(crate) fn extract_wind_zones(
+    mut extracted: ResMut<ExtractedWindZones>,
+    zones_q: Extract<Query<(Entity, &GrassWindZone, &GlobalTransform)>>,
+) {
+    let mut zones: Vec<_> = zones_q.iter().collect();
+    // Sorted by entity among equally strong zones, so that the same zones are kept every frame.
+    zones.sort_by(|(a, zone_a, _), (b, zone_b, _)| {
+        zone_b
+            .strength
+            .abs()
+            .total_cmp(&zone_a.strength.abs())
+            .then(a.cmp(b))
+    });
+    let zones: Vec<_> = zones
+        .into_iter()
+        .take(MAX_WIND_ZONES)
+        .map(|(_, zone, transform)| WindZoneUniform {
+            position: transform.translation(),
+            kind: match zone.kind {
+                WindZoneKind::Radial => 0,
+                WindZoneKind::Directional => 1,
+                WindZoneKind::Vortex => 2,
+            },
+            direction: transform.forward().xz().normalize_or_zero(),
+            radius: zone.radius,
+            strength: zone.strength,
+            falloff: zone.falloff,
+            _padding: [0; 3],
+        })
+        .collect();
+    // Assigned only when a zone differs from the last frame, so that `prepare_grass_wind` skips
+    // uploading zones that stand still.
+    if extracted.zones != zones {
+        extracted.zones = zones;
+    }
+}
+
+/// Uploads the [`GrassWind`] and the wind zones when they change, and binds the noise texture
+/// once it is prepared.
+pub(crate) fn prepare_grass_wind(
+    wind: Res<GrassWind>,
+    zones: Res<ExtractedWindZones>,
+    wind_bindings: Option<ResMut<GrassWindBindings>>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(mut wind_bindings) = wind_bindings else {
+        return;
+    };
+    if wind.is_changed() {
+        render_queue.write_buffer(
+            &wind_bindings.buffer,
+            0,
+            bytemuck::bytes_of(&GrassWindUniform::new(&wind)),
+        );
+    }
+    if zones.is_changed() {
+        let mut uniform = WindZonesUniform::zeroed();
+        uniform.count = zones.zones.len() as u32;
+        uniform.zones[..zones.zones.len()].copy_from_slice(&zones.zones);
+        render_queue.write_buffer(&wind_bindings.zone_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+    let noise = wind
+        .noise
+        .as_ref()
+        .and_then(|noise| images.get(&noise.image))
+        .unwrap_or(&fallback_image.d2);
+    if noise.texture_view.id() != wind_bindings.noise.id() {
+        wind_bindings.noise = noise.texture_view.clone();
+    }
+}
@@ -1,104 +1,546 @@
 use bevy::{
     prelude::*,
-    render::{mesh::VertexAttributeValues, render_resource::PrimitiveTopology},
+    render::{
+        mesh::{Indices, MeshVertexAttributeId, VertexAttributeValues},
+        render_resource::PrimitiveTopology,
+    },
+    utils::HashMap,
 };
 use rand::prelude::*;
 use rand_distr::{Distribution, WeightedAliasIndex};
 
-type Triangle = [Vec3; 3];
+use crate::texture::{TextureChannel, TextureMap};
+
+/// A point sampled on the surface of a mesh, with the mesh attributes interpolated at it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshSample {
+    pub position: Vec3,
+    /// The interpolated, normalized vertex normal.
+    pub normal: Vec3,
+    /// The interpolated `Mesh::ATTRIBUTE_UV_0`, or zero if the mesh has no UVs.
+    pub uv: Vec2,
+    /// The weights of the source triangle's three vertices at `position`.
+    pub barycentric: Vec3,
+    /// The index of the source triangle, counting the mesh's triangles in draw order.
+    pub triangle: usize,
+}
 
 pub trait MeshSampler {
-    fn sample_tri_list(&self, mesh: &Mesh) -> Vec<Vec3>;
-    fn sample_tri_strip(&self, mesh: &Mesh) -> Vec<Vec3>;
-    fn sample(&self, mesh: &Mesh) -> Vec<Vec3> {
+    fn sample_tri_list(&self, mesh: &Mesh) -> Vec<MeshSample>;
+    fn sample_tri_strip(&self, mesh: &Mesh) -> Vec<MeshSample>;
+    fn sample_attributes(&self, mesh: &Mesh) -> Vec<MeshSample> {
         match mesh.primitive_topology() {
             PrimitiveTopology::PointList
             | PrimitiveTopology::LineList
-            | PrimitiveTopology::LineStrip => return vec![],
-            PrimitiveTopology::TriangleList => return self.sample_tri_list(mesh),
-            PrimitiveTopology::TriangleStrip => return self.sample_tri_strip(mesh),
+            | PrimitiveTopology::LineStrip => vec![],
+            PrimitiveTopology::TriangleList => self.sample_tri_list(mesh),
+            PrimitiveTopology::TriangleStrip => self.sample_tri_strip(mesh),
+        }
+    }
+    /// Samples only the positions of the points, see [`MeshSampler::sample_attributes`].
+    fn sample(&self, mesh: &Mesh) -> Vec<Vec3> {
+        self.sample_attributes(mesh)
+            .into_iter()
+            .map(|sample| sample.position)
+            .collect()
+    }
+}
+
+/// The direction grass grows towards, used to measure how steep a surface is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpDirection {
+    /// The same direction everywhere, e.g. `Vec3::Y` for terrain or `Vec3::NEG_Y` for cave
+    /// ceilings.
+    Axis(Vec3),
+    /// Pointing away from `center`, e.g. for spherical planets.
+    Radial { center: Vec3 },
+}
+
+impl Default for UpDirection {
+    fn default() -> Self {
+        Self::Axis(Vec3::Y)
+    }
+}
+
+impl UpDirection {
+    /// Returns the normalized up direction at `point`.
+    pub fn at(&self, point: Vec3) -> Vec3 {
+        match self {
+            UpDirection::Axis(axis) => axis.normalize_or_zero(),
+            UpDirection::Radial { center } => (point - *center).normalize_or_zero(),
+        }
+    }
+
+    /// Whether this and `other` point the same way everywhere, with each component of their
+    /// normalized axes or centers at most `max_abs_diff` apart.
+    pub fn abs_diff_eq(&self, other: &Self, max_abs_diff: f32) -> bool {
+        match (self, other) {
+            (UpDirection::Axis(a), UpDirection::Axis(b)) => a
+                .normalize_or_zero()
+                .abs_diff_eq(b.normalize_or_zero(), max_abs_diff),
+            (UpDirection::Radial { center: a }, UpDirection::Radial { center: b }) => {
+                a.abs_diff_eq(*b, max_abs_diff)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns this up direction expressed in the space that `matrix` transforms points into.
+    pub fn transformed(&self, matrix: Mat4) -> Self {
+        match self {
+            UpDirection::Axis(axis) => UpDirection::Axis(matrix.transform_vector3(*axis)),
+            UpDirection::Radial { center } => UpDirection::Radial {
+                center: matrix.transform_point3(*center),
+            },
+        }
+    }
+}
+
+/// Limits grass to surfaces whose slope, the angle in degrees between the surface normal and
+/// the up direction, lies within `min_angle..=max_angle`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlopeLimit {
+    pub min_angle: f32,
+    pub max_angle: f32,
+    pub up: UpDirection,
+}
+
+impl Default for SlopeLimit {
+    fn default() -> Self {
+        Self {
+            min_angle: 0.,
+            max_angle: 90.,
+            up: UpDirection::default(),
+        }
+    }
+}
+
+impl SlopeLimit {
+    /// Returns whether a surface at `position` facing `normal` may grow grass.
+    pub fn allows(&self, position: Vec3, normal: Vec3) -> bool {
+        let cos = self.up.at(position).dot(normal.normalize_or_zero());
+        let angle = cos.clamp(-1., 1.).acos().to_degrees();
+        (self.min_angle..=self.max_angle).contains(&angle)
+    }
+}
+
+/// Returns the vertex indices of the mesh, or `0..vertex_count` if the mesh is not indexed.
+fn vertex_indices(mesh: &Mesh) -> Vec<usize> {
+    match mesh.indices() {
+        Some(indices) => indices.iter().collect(),
+        None => (0..mesh.count_vertices()).collect(),
+    }
+}
+
+/// Splits the vertex indices of a triangle list into triangles.
+fn tri_list_triangles(mesh: &Mesh) -> Vec<[usize; 3]> {
+    vertex_indices(mesh)
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect()
+}
+
+/// Splits the vertex indices of a triangle strip into triangles.
+///
+/// Every other triangle in a strip has its winding flipped so that all triangles face the
+/// same way. Indexed strips are split into separate strips at primitive restart values.
+fn tri_strip_triangles(mesh: &Mesh) -> Vec<[usize; 3]> {
+    let strips: Vec<Vec<usize>> = match mesh.indices() {
+        Some(Indices::U16(indices)) => indices
+            .split(|index| *index == u16::MAX)
+            .map(|strip| strip.iter().map(|index| *index as usize).collect())
+            .collect(),
+        Some(Indices::U32(indices)) => indices
+            .split(|index| *index == u32::MAX)
+            .map(|strip| strip.iter().map(|index| *index as usize).collect())
+            .collect(),
+        None => vec![(0..mesh.count_vertices()).collect()],
+    };
+    strips
+        .iter()
+        .flat_map(|strip| {
+            strip.windows(3).enumerate().map(|(i, window)| {
+                if i % 2 == 0 {
+                    [window[0], window[1], window[2]]
+                } else {
+                    [window[1], window[0], window[2]]
+                }
+            })
+        })
+        .collect()
+}
+
+/// Masks grass by one channel of a vertex attribute, e.g. a mask painted into
+/// `Mesh::ATTRIBUTE_COLOR` and exported through glTF.
+///
+/// Triangles are weighted by the average mask of their vertices, and samples where the
+/// interpolated mask is below `cutoff` are rejected. Meshes without the attribute get no grass.
+#[derive(Clone, Copy, Debug)]
+pub struct VertexMask {
+    pub attribute: MeshVertexAttributeId,
+    pub channel: usize,
+    pub cutoff: f32,
+}
+
+impl VertexMask {
+    /// Reads the masked channel of every vertex, or `None` if the mesh has no such channel.
+    fn values(&self, mesh: &Mesh) -> Option<Vec<f32>> {
+        fn channel<T: Copy, const N: usize>(
+            values: &[[T; N]],
+            channel: usize,
+            convert: impl Fn(T) -> f32,
+        ) -> Option<Vec<f32>> {
+            (channel < N).then(|| values.iter().map(|v| convert(v[channel])).collect())
+        }
+        let unorm8 = |v: u8| v as f32 / u8::MAX as f32;
+        let unorm16 = |v: u16| v as f32 / u16::MAX as f32;
+        match mesh.attribute(self.attribute)? {
+            VertexAttributeValues::Float32(values) => (self.channel == 0).then(|| values.clone()),
+            VertexAttributeValues::Float32x2(values) => channel(values, self.channel, |v| v),
+            VertexAttributeValues::Float32x3(values) => channel(values, self.channel, |v| v),
+            VertexAttributeValues::Float32x4(values) => channel(values, self.channel, |v| v),
+            VertexAttributeValues::Unorm8x2(values) => channel(values, self.channel, unorm8),
+            VertexAttributeValues::Unorm8x4(values) => channel(values, self.channel, unorm8),
+            VertexAttributeValues::Unorm16x2(values) => channel(values, self.channel, unorm16),
+            VertexAttributeValues::Unorm16x4(values) => channel(values, self.channel, unorm16),
+            _ => None,
+        }
+    }
+}
+
+/// The vertex attributes of a mesh that samples are interpolated from.
+struct MeshSurface<'a> {
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    uvs: Option<&'a [[f32; 2]]>,
+    /// The vertex mask values, with the cutoff below which samples are rejected.
+    mask: Option<(Vec<f32>, f32)>,
+}
+
+impl<'a> MeshSurface<'a> {
+    fn new(mesh: &'a Mesh, mask: Option<&VertexMask>) -> Option<Self> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return None;
+        };
+        let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+        else {
+            return None;
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs.as_slice()),
+            _ => None,
+        };
+        let mask = match mask {
+            Some(mask) => Some((mask.values(mesh)?, mask.cutoff)),
+            None => None,
+        };
+        Some(Self {
+            positions,
+            normals,
+            uvs,
+            mask,
+        })
+    }
+
+    /// Returns the mask weights of the triangle's vertices, or `1` for every vertex if the
+    /// surface is not masked.
+    fn mask_weights(&self, [a, b, c]: [usize; 3]) -> Option<Vec3> {
+        match &self.mask {
+            Some((values, _)) => Some(Vec3::new(*values.get(a)?, *values.get(b)?, *values.get(c)?)),
+            None => Some(Vec3::ONE),
+        }
+    }
+
+    /// Whether a sample at `barycentric` on the triangle passes the mask cutoff.
+    fn passes_mask(&self, triangle: &SurfaceTriangle, barycentric: Vec3) -> bool {
+        match &self.mask {
+            Some((_, cutoff)) => triangle.mask.dot(barycentric) >= *cutoff,
+            None => true,
         }
     }
+
+    fn sample(&self, triangle: &SurfaceTriangle, barycentric: Vec3) -> MeshSample {
+        let [a, b, c] = triangle.vertices;
+        let normal = Vec3::from(self.normals[a]) * barycentric.x
+            + Vec3::from(self.normals[b]) * barycentric.y
+            + Vec3::from(self.normals[c]) * barycentric.z;
+        let uv = self.uvs.map_or(Vec2::ZERO, |uvs| {
+            let uv = |i: usize| uvs.get(i).copied().map_or(Vec2::ZERO, Vec2::from);
+            uv(a) * barycentric.x + uv(b) * barycentric.y + uv(c) * barycentric.z
+        });
+        MeshSample {
+            position: triangle.positions[0] * barycentric.x
+                + triangle.positions[1] * barycentric.y
+                + triangle.positions[2] * barycentric.z,
+            normal: normal.normalize_or_zero(),
+            uv,
+            barycentric,
+            triangle: triangle.index,
+        }
+    }
+}
+
+/// A triangle of the mesh that grass may be placed on.
+struct SurfaceTriangle {
+    /// The index of the triangle, counting the mesh's triangles in draw order.
+    index: usize,
+    vertices: [usize; 3],
+    positions: [Vec3; 3],
+    /// The vertex mask values of the triangle's vertices.
+    mask: Vec3,
+    /// The weight of the triangle for area-weighted sampling, scaled by its average mask.
+    area: f32,
+}
+
+/// Collects the triangles of the mesh whose averaged vertex normal is within the slope limit.
+fn surface_triangles(
+    surface: &MeshSurface,
+    indices: &[[usize; 3]],
+    slope: &SlopeLimit,
+) -> Vec<SurfaceTriangle> {
+    indices
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &vertices)| {
+            let [a, b, c] = vertices;
+            let positions = [
+                Vec3::from(*surface.positions.get(a)?),
+                Vec3::from(*surface.positions.get(b)?),
+                Vec3::from(*surface.positions.get(c)?),
+            ];
+            let normal = Vec3::from(*surface.normals.get(a)?)
+                + Vec3::from(*surface.normals.get(b)?)
+                + Vec3::from(*surface.normals.get(c)?);
+
+            if !slope.allows((positions[0] + positions[1] + positions[2]) / 3., normal) {
+                return None;
+            }
+            let mask = surface.mask_weights(vertices)?;
+            let area = (positions[0] - positions[1])
+                .cross(positions[0] - positions[2])
+                .length()
+                * (mask.x + mask.y + mask.z)
+                / 3.;
+            // Degenerate or non-finite positions and masks leave nothing to sample.
+            if !area.is_finite() || area <= 0. {
+                return None;
+            }
+            Some(SurfaceTriangle {
+                index,
+                vertices,
+                positions,
+                mask,
+                area,
+            })
+        })
+        .collect()
+}
+
+/// Returns uniformly distributed barycentric coordinates on a triangle.
+fn sample_barycentric(rng: &mut impl Rng) -> Vec3 {
+    let mut u = rng.gen::<f32>();
+    let mut v = rng.gen::<f32>();
+    if u + v > 1. {
+        u = 1. - u;
+        v = 1. - v;
+    }
+    Vec3::new(1. - u - v, u, v)
+}
+
+/// Draws `count` samples uniformly distributed over the area of `triangles`.
+fn sample_surface(
+    rng: &mut impl Rng,
+    surface: &MeshSurface,
+    triangles: &[SurfaceTriangle],
+    count: usize,
+) -> Vec<MeshSample> {
+    if triangles.is_empty() {
+        return vec![];
+    }
+    let areas = triangles
+        .iter()
+        .map(|triangle| triangle.area)
+        .collect::<Vec<f32>>();
+    let Ok(dist) = WeightedAliasIndex::new(areas) else {
+        return vec![];
+    };
+    (0..count)
+        .filter_map(|_| {
+            let triangle = &triangles[dist.sample(rng)];
+            let barycentric = sample_barycentric(rng);
+            surface
+                .passes_mask(triangle, barycentric)
+                .then(|| surface.sample(triangle, barycentric))
+        })
+        .collect()
+}
+
+/// Scales the local sampling density by one channel of a texture, looked up through the mesh's
+/// `Mesh::ATTRIBUTE_UV_0`. A value of `1` keeps the full density and `0` places no grass.
+#[derive(Clone, Debug)]
+pub struct DensityMap {
+    pub texture: TextureMap,
+    pub channel: TextureChannel,
+}
+
+impl DensityMap {
+    /// Keeps each sample with a probability equal to the map's value at its UV.
+    fn thin(&self, rng: &mut impl Rng, samples: Vec<MeshSample>) -> Vec<MeshSample> {
+        samples
+            .into_iter()
+            .filter(|sample| {
+                rng.gen::<f32>() < self.texture.sample_channel(sample.uv, self.channel)
+            })
+            .collect()
+    }
 }
 
 pub struct UniformRandomSampler {
     pub density: f32,
-    pub threshold: f32,
+    pub slope: SlopeLimit,
+    pub density_map: Option<DensityMap>,
+    pub vertex_mask: Option<VertexMask>,
+    /// Seed for the random number generator. Sampling the same mesh with the same density,
+    /// slope limit and seed always produces the same points.
+    pub seed: u64,
 }
 
 impl Default for UniformRandomSampler {
     fn default() -> Self {
         Self {
             density: 1.,
-            threshold: 0.,
+            slope: SlopeLimit::default(),
+            density_map: None,
+            vertex_mask: None,
+            seed: 0,
         }
     }
 }
 
 impl UniformRandomSampler {
-    fn sample_triangle(&self, triangle: Triangle) -> Vec3 {
-        let mut u = fastrand::f32();
-        let mut v = fastrand::f32();
-        if u + v > 1. {
-            u = 1. - u;
-            v = 1. - v;
+    fn sample_triangles(&self, mesh: &Mesh, indices: &[[usize; 3]]) -> Vec<MeshSample> {
+        let Some(surface) = MeshSurface::new(mesh, self.vertex_mask.as_ref()) else {
+            return vec![];
+        };
+        let triangles = surface_triangles(&surface, indices, &self.slope);
+        let mesh_sa: f32 = triangles.iter().map(|triangle| triangle.area).sum();
+        let sample_count = (mesh_sa * self.density) as usize;
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let samples = sample_surface(&mut rng, &surface, &triangles, sample_count);
+        match &self.density_map {
+            Some(density_map) => density_map.thin(&mut rng, samples),
+            None => samples,
         }
-        triangle[0] + (triangle[1] - triangle[0]) * u + (triangle[2] - triangle[0]) * v
     }
 }
 
 impl MeshSampler for UniformRandomSampler {
-    fn sample_tri_list(&self, mesh: &Mesh) -> Vec<Vec3> {
-        let mesh_duped = mesh.clone().with_duplicated_vertices();
-        let VertexAttributeValues::Float32x3(positions) =
-            mesh_duped.attribute(Mesh::ATTRIBUTE_POSITION).unwrap()
-        else {
-            return vec![];
-        };
-        let VertexAttributeValues::Float32x3(normals) =
-            mesh_duped.attribute(Mesh::ATTRIBUTE_NORMAL).unwrap()
-        else {
+    fn sample_tri_list(&self, mesh: &Mesh) -> Vec<MeshSample> {
+        self.sample_triangles(mesh, &tri_list_triangles(mesh))
+    }
+
+    fn sample_tri_strip(&self, mesh: &Mesh) -> Vec<MeshSample> {
+        self.sample_triangles(mesh, &tri_strip_triangles(mesh))
+    }
+}
+
+/// Samples points that are never closer than `min_distance` to each other, giving an even,
+/// clump-free (blue noise) distribution.
+///
+/// Candidates are drawn uniformly over the surface and accepted in order when no accepted point
+/// lies within `min_distance`, using a spatial hash over the mesh's 3D positions. Spacing is
+/// therefore enforced across triangle boundaries. The result has at most as many points as a
+/// [`UniformRandomSampler`] with the same density, and fewer if `min_distance` is too large to
+/// fit them all.
+pub struct PoissonDiskSampler {
+    pub density: f32,
+    pub slope: SlopeLimit,
+    pub density_map: Option<DensityMap>,
+    pub vertex_mask: Option<VertexMask>,
+    pub seed: u64,
+    pub min_distance: f32,
+}
+
+impl Default for PoissonDiskSampler {
+    fn default() -> Self {
+        Self {
+            density: 1.,
+            slope: SlopeLimit::default(),
+            density_map: None,
+            vertex_mask: None,
+            seed: 0,
+            min_distance: 0.1,
+        }
+    }
+}
+
+impl PoissonDiskSampler {
+    /// How many uniform candidates are drawn for every point the density asks for.
+    const CANDIDATES_PER_POINT: usize = 4;
+
+    fn sample_triangles(&self, mesh: &Mesh, indices: &[[usize; 3]]) -> Vec<MeshSample> {
+        let Some(surface) = MeshSurface::new(mesh, self.vertex_mask.as_ref()) else {
             return vec![];
         };
+        let triangles = surface_triangles(&surface, indices, &self.slope);
+        let mesh_sa: f32 = triangles.iter().map(|triangle| triangle.area).sum();
+        let sample_count = (mesh_sa * self.density) as usize;
 
-        let mut mesh_sa = 0.;
-        let triangles: Vec<(Triangle, f32)> = positions
-            .into_iter()
-            .zip(normals.into_iter())
-            .map(|(v, n)| (Vec3::from_array(*v), Vec3::from_array(*n)))
-            .collect::<Vec<(Vec3, Vec3)>>()
-            .chunks(3)
-            .filter_map(|triangle| {
-                let [a, b, c] = triangle[..] else { return None };
-
-                let dot = Vec3::Y.dot((a.1 + b.1 + c.1).normalize());
-                if dot < self.threshold {
-                    return None;
-                }
-                let area = (a.0 - b.0).cross(a.0 - c.0).length();
-                if area > 0. {
-                    mesh_sa += area;
-                } else {
-                    return None;
-                }
-                Some(([a.0, b.0, c.0], area))
-            })
-            .collect();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut candidates = sample_surface(
+            &mut rng,
+            &surface,
+            &triangles,
+            sample_count * Self::CANDIDATES_PER_POINT,
+        );
+        if let Some(density_map) = &self.density_map {
+            candidates = density_map.thin(&mut rng, candidates);
+        }
+        let sample_count = candidates.len() / Self::CANDIDATES_PER_POINT;
+        if self.min_distance <= 0. {
+            return candidates.into_iter().take(sample_count).collect();
+        }
 
-        let sample_count = (mesh_sa * self.density) as usize;
+        let cell = |point: Vec3| (point / self.min_distance).floor().as_ivec3();
+        let mut grid: HashMap<IVec3, Vec<Vec3>> = HashMap::default();
+        let mut points = Vec::with_capacity(sample_count);
+        for candidate in candidates {
+            if points.len() >= sample_count {
+                break;
+            }
+            let center = cell(candidate.position);
+            let crowded = (-1..=1).any(|x| {
+                (-1..=1).any(|y| {
+                    (-1..=1).any(|z| {
+                        grid.get(&(center + IVec3::new(x, y, z)))
+                            .is_some_and(|neighbours| {
+                                neighbours.iter().any(|neighbour| {
+                                    neighbour.distance_squared(candidate.position)
+                                        < self.min_distance * self.min_distance
+                                })
+                            })
+                    })
+                })
+            });
+            if !crowded {
+                grid.entry(center).or_default().push(candidate.position);
+                points.push(candidate);
+            }
+        }
+        points
+    }
+}
 
-        let areas = &triangles
-            .iter()
-            .map(|(_, area)| *area)
-            .collect::<Vec<f32>>()[..];
-        let dist = WeightedAliasIndex::new(areas.to_vec()).unwrap();
-        let mut rng = thread_rng();
-        (0..sample_count)
-            .map(|_| self.sample_triangle(triangles[dist.sample(&mut rng)].0))
-            .collect()
+impl MeshSampler for PoissonDiskSampler {
+    fn sample_tri_list(&self, mesh: &Mesh) -> Vec<MeshSample> {
+        self.sample_triangles(mesh, &tri_list_triangles(mesh))
     }
 
-    fn sample_tri_strip(&self, _mesh: &Mesh) -> Vec<Vec3> {
-        todo!() // work in progress
+    fn sample_tri_strip(&self, mesh: &Mesh) -> Vec<MeshSample> {
+        self.sample_triangles(mesh, &tri_strip_triangles(mesh))
     }
 }
@@ -0,0 +1,137 @@
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+
+/// A channel of a [`TextureMap`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextureChannel {
+    #[default]
+    R,
+    G,
+    B,
+    A,
+}
+
+/// A CPU-side copy of an [`Image`] that can be looked up by UV coordinates.
+///
+/// Values are stored as they are encoded in the image, so channels of sRGB images are not
+/// converted to linear.
+#[derive(Clone, Debug)]
+pub struct TextureMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec4>,
+    srgb: bool,
+}
+
+impl TextureMap {
+    /// Copies the pixels of an uncompressed 2D image. Returns `None` for texture formats that
+    /// cannot be read on the CPU.
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let format = image.texture_descriptor.format;
+        let unorm8 = |byte: &u8| *byte as f32 / u8::MAX as f32;
+        let unorm16 =
+            |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32;
+        let float32 = |bytes: &[u8]| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        let pixels: Vec<Vec4> = match format {
+            TextureFormat::R8Unorm => image
+                .data
+                .iter()
+                .map(|r| Vec4::new(unorm8(r), 0., 0., 1.))
+                .collect(),
+            TextureFormat::Rg8Unorm => image
+                .data
+                .chunks_exact(2)
+                .map(|p| Vec4::new(unorm8(&p[0]), unorm8(&p[1]), 0., 1.))
+                .collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => image
+                .data
+                .chunks_exact(4)
+                .map(|p| Vec4::new(unorm8(&p[0]), unorm8(&p[1]), unorm8(&p[2]), unorm8(&p[3])))
+                .collect(),
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => image
+                .data
+                .chunks_exact(4)
+                .map(|p| Vec4::new(unorm8(&p[2]), unorm8(&p[1]), unorm8(&p[0]), unorm8(&p[3])))
+                .collect(),
+            TextureFormat::R16Unorm => image
+                .data
+                .chunks_exact(2)
+                .map(|p| Vec4::new(unorm16(p), 0., 0., 1.))
+                .collect(),
+            TextureFormat::Rgba16Unorm => image
+                .data
+                .chunks_exact(8)
+                .map(|p| {
+                    Vec4::new(
+                        unorm16(&p[0..2]),
+                        unorm16(&p[2..4]),
+                        unorm16(&p[4..6]),
+                        unorm16(&p[6..8]),
+                    )
+                })
+                .collect(),
+            TextureFormat::R32Float => image
+                .data
+                .chunks_exact(4)
+                .map(|p| Vec4::new(float32(p), 0., 0., 1.))
+                .collect(),
+            TextureFormat::Rgba32Float => image
+                .data
+                .chunks_exact(16)
+                .map(|p| {
+                    Vec4::new(
+                        float32(&p[0..4]),
+                        float32(&p[4..8]),
+                        float32(&p[8..12]),
+                        float32(&p[12..16]),
+                    )
+                })
+                .collect(),
+            _ => return None,
+        };
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        if width == 0 || height == 0 || pixels.len() < width * height {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            pixels,
+            srgb: format.is_srgb(),
+        })
+    }
+
+    /// Whether the values of the map are sRGB encoded.
+    pub fn is_srgb(&self) -> bool {
+        self.srgb
+    }
+
+    fn pixel(&self, x: isize, y: isize) -> Vec4 {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Returns the bilinearly filtered value at `uv`. The map repeats outside of `0..1`.
+    pub fn sample(&self, uv: Vec2) -> Vec4 {
+        let texel = uv * Vec2::new(self.width as f32, self.height as f32) - 0.5;
+        let base = texel.floor();
+        let t = texel - base;
+        let (x, y) = (base.x as isize, base.y as isize);
+        let top = self.pixel(x, y).lerp(self.pixel(x + 1, y), t.x);
+        let bottom = self.pixel(x, y + 1).lerp(self.pixel(x + 1, y + 1), t.x);
+        top.lerp(bottom, t.y)
+    }
+
+    /// Returns the bilinearly filtered value of one channel at `uv`.
+    pub fn sample_channel(&self, uv: Vec2, channel: TextureChannel) -> f32 {
+        let value = self.sample(uv);
+        match channel {
+            TextureChannel::R => value.x,
+            TextureChannel::G => value.y,
+            TextureChannel::B => value.z,
+            TextureChannel::A => value.w,
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::f32::consts::TAU;
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use rand::prelude::*;
+
+use crate::render::wind::{extract_wind_zones, prepare_grass_wind, ExtractedWindZones};
+
+/// The default noise texture of [`WindNoise`], generated by [`GrassWindPlugin`].
+pub const GRASS_WIND_NOISE: Handle<Image> =
+    Handle::weak_from_u128(0x5f3a_91c2_d04e_4b7a_8e21_6c9d_30b5_f7e4);
+
+/// The wind that sways all grass blades. Insert it as a resource to change it.
+///
+/// Blades sway along `direction`, in gusts that roll across the world and are
+/// broken up by a scrolling [`WindNoise`]. Each blade also flutters
+/// on its own, as strongly as the gusts.
+#[derive(Resource, ExtractResource, Clone, Debug, PartialEq)]
+pub struct GrassWind {
+    /// The direction the wind blows in, on the world XZ plane. It does not need to be normalized.
+    pub direction: Vec2,
+    /// How far a gust pushes the tip of a blade sideways, per unit of blade height.
+    ///
+    /// The bounds of grass chunks are padded by the sway. When the strength rises above what
+    /// they were padded for, the bounds of the existing chunks grow to fit it.
+    pub strength: f32,
+    /// How many gusts pass a blade per second.
+    pub gust_frequency: f32,
+    /// The distance along `direction` between the crests of neighbouring gusts, divided by `2π`.
+    /// Larger scales make broader gusts that sway more blades together.
+    pub turbulence_scale: f32,
+    /// Noise that scrolls along the wind to break the gusts up into travelling fronts, or `None`
+    /// to sway all blades by the full strength of the gusts.
+    pub noise: Option<WindNoise>,
+}
+
+/// A tiling noise texture laid over the world XZ plane, which scales the gusts of the
+/// [`GrassWind`]: blades under white texels sway by the full strength of the gusts, and blades
+/// under black texels only flutter. The red channel is read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindNoise {
+    /// The texture, which repeats in both directions. Until it is loaded, the gusts are not scaled.
+    pub image: Handle<Image>,
+    /// The world size of one tile of the texture.
+    pub scale: f32,
+    /// How fast the texture scrolls along the wind direction, in world units per second.
+    pub scroll_speed: f32,
+}
+
+impl Default for WindNoise {
+    fn default() -> Self {
+        Self {
+            image: GRASS_WIND_NOISE,
+            scale: 60.,
+            scroll_speed: 6.,
+        }
+    }
+}
+
+impl WindNoise {
+    /// Generates a `size` by `size` texture of [`tiling_noise`].
+    pub fn generate(size: u32, cells: u32, octaves: u32, seed: u64) -> Image {
+        let data = tiling_noise(size, cells, octaves, seed)
+            .into_iter()
+            .map(|value| (value * 255.).round() as u8)
+            .collect();
+        Image::new(
+            Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R8Unorm,
+        )
+    }
+}
+
+/// Returns `size` by `size` values of fractal value noise between `0` and `1`, in rows, that
+/// tile seamlessly. The first octave is a grid of `cells` by `cells` random values, and each of
+/// the further `octaves` doubles the cells and halves the weight.
+pub fn tiling_noise(size: u32, cells: u32, octaves: u32, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut noise = vec![0.; (size * size) as usize];
+    let mut weight = 1.;
+    let mut total_weight = 0.;
+    for octave in 0..=octaves {
+        let cells = (cells << octave).max(1) as usize;
+        let lattice: Vec<f32> = (0..cells * cells).map(|_| rng.gen()).collect();
+        let value = |x: usize, y: usize| lattice[(y % cells) * cells + x % cells];
+        for (i, noise) in noise.iter_mut().enumerate() {
+            let (x, y) = (i % size as usize, i / size as usize);
+            let p = Vec2::new(x as f32, y as f32) / size as f32 * cells as f32;
+            let (x0, y0) = (p.x as usize, p.y as usize);
+            // Smoothstep hides the lattice.
+            let t = p.fract();
+            let t = t * t * (Vec2::splat(3.) - 2. * t);
+            let top = value(x0, y0) + (value(x0 + 1, y0) - value(x0, y0)) * t.x;
+            let bottom = value(x0, y0 + 1) + (value(x0 + 1, y0 + 1) - value(x0, y0 + 1)) * t.x;
+            *noise += (top + (bottom - top) * t.y) * weight;
+        }
+        total_weight += weight;
+        weight *= 0.5;
+    }
+    noise.iter_mut().for_each(|value| *value /= total_weight);
+    noise
+}
+
+impl Default for GrassWind {
+    fn default() -> Self {
+        Self {
+            direction: Vec2::X,
+            strength: 0.3,
+            gust_frequency: 1. / TAU,
+            turbulence_scale: 100.,
+            noise: Some(WindNoise::default()),
+        }
+    }
+}
+
+impl GrassWind {
+    /// How far the wind can push the tip of a blade sideways, per unit of blade height: the gusts
+    /// and the flutter of the blade both sway it by up to `strength`.
+    pub fn max_sway(&self) -> f32 {
+        2. * self.strength.abs()
+    }
+}
+
+/// The most [`GrassWindZone`]s that sway grass at once. Mirrors `MAX_WIND_ZONES` in `wind.wgsl`.
+pub const MAX_WIND_ZONES: usize = 16;
+
+/// Pushes grass blades around the entity, on top of the [`GrassWind`], for explosions, rotor
+/// wash or fans.
+///
+/// Blades within `radius` of the entity are pushed by up to `strength` per unit of blade height,
+/// on the world XZ plane. The pushes of overlapping zones add up. Only the [`MAX_WIND_ZONES`]
+/// strongest zones sway grass, and the bounds of grass chunks grow to fit their summed strength
+/// like they do for the strength of the [`GrassWind`].
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct GrassWindZone {
+    pub kind: WindZoneKind,
+    pub radius: f32,
+    /// Negative strengths push the other way, such as towards the entity for a radial zone.
+    pub strength: f32,
+    /// How sharply the push fades towards the edge of the zone. The push is scaled by
+    /// `(1 - distance / radius)` to the power of `falloff`, so `0` pushes evenly and `1` fades
+    /// linearly.
+    pub falloff: f32,
+}
+
+impl Default for GrassWindZone {
+    fn default() -> Self {
+        Self {
+            kind: WindZoneKind::default(),
+            radius: 5.,
+            strength: 1.,
+            falloff: 1.,
+        }
+    }
+}
+
+/// The direction a [`GrassWindZone`] pushes blades in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WindZoneKind {
+    /// Away from the entity.
+    #[default]
+    Radial,
+    /// Along the forward direction of the entity.
+    Directional,
+    /// Counterclockwise around the entity, seen from above.
+    Vortex,
+}
+
+impl GrassWindZone {
+    /// How far the zone at `transform` pushes the tip of a blade at `position`, per unit of blade
+    /// height, on the world XZ plane. Mirrors `wind_zone_offset` in `wind.wgsl`.
+    pub fn push(&self, transform: &GlobalTransform, position: Vec3) -> Vec2 {
+        let center = transform.translation();
+        let distance = position.distance(center);
+        if distance >= self.radius {
+            return Vec2::ZERO;
+        }
+        let away = (position - center).xz().normalize_or_zero();
+        let direction = match self.kind {
+            WindZoneKind::Radial => away,
+            WindZoneKind::Directional => transform.forward().xz().normalize_or_zero(),
+            WindZoneKind::Vortex => Vec2::new(away.y, -away.x),
+        };
+        direction * self.strength * (1. - distance / self.radius).powf(self.falloff)
+    }
+}
+
+/// How far the strongest [`MAX_WIND_ZONES`] of `zones` can push the tip of a blade sideways
+/// together, per unit of blade height.
+pub fn max_zone_sway<'a>(zones: impl IntoIterator<Item = &'a GrassWindZone>) -> f32 {
+    let mut strengths: Vec<f32> = zones.into_iter().map(|zone| zone.strength.abs()).collect();
+    strengths.sort_by(|a, b| b.total_cmp(a));
+    strengths.into_iter().take(MAX_WIND_ZONES).sum()
+}
+
+/// Uploads the [`GrassWind`] and the [`GrassWindZone`]s to the GPU.
+pub struct GrassWindPlugin;
+
+impl Plugin for GrassWindPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GrassWind>()
+            .add_plugins(ExtractResourcePlugin::<GrassWind>::default());
+        app.sub_app_mut(RenderApp)
+            .init_resource::<GrassWind>()
+            .init_resource::<ExtractedWindZones>()
+            .add_systems(ExtractSchedule, extract_wind_zones)
+            .add_systems(
+                Render,
+                prepare_grass_wind.in_set(RenderSet::PrepareResources),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        if let Some(mut images) = app.world.get_resource_mut::<Assets<Image>>() {
+            images.insert(GRASS_WIND_NOISE, WindNoise::generate(128, 4, 2, 0));
+        }
+    }
+}
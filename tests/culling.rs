@@ -0,0 +1,74 @@
+use bevy::{
+    prelude::*,
+    render::{camera::CameraProjection, primitives::Frustum},
+};
+use frosty_grass::culling::{cull_instances, CullingView};
+
+/// A camera at the origin looking down -Z.
+fn view() -> CullingView {
+    let projection = PerspectiveProjection::default().get_projection_matrix();
+    CullingView {
+        frustum: Frustum::from_view_projection(&projection),
+        position: Vec3::ZERO,
+    }
+}
+
+#[test]
+fn culls_instances_outside_the_frustum() {
+    let instances = [
+        Vec4::new(0., 0., -5., 1.),
+        Vec4::new(0., 0., 5., 1.),
+        Vec4::new(100., 0., -5., 1.),
+        Vec4::new(0., 0., -1000., 1.),
+    ];
+    let kept = cull_instances(&view(), &GlobalTransform::IDENTITY, 0.5, &instances, |_| 1.);
+    // The far plane is ignored, so distant instances are only thinned out by `keep_fraction`.
+    assert_eq!(kept, vec![0, 3]);
+}
+
+#[test]
+fn keeps_instances_that_reach_into_the_frustum() {
+    // Just behind the near plane, but with a mesh reaching in front of it.
+    let instances = [Vec4::new(0., 0., 0.5, 1.)];
+    let kept = cull_instances(&view(), &GlobalTransform::IDENTITY, 1., &instances, |_| 1.);
+    assert_eq!(kept, vec![0]);
+
+    // A smaller scale shrinks the instance's bounds.
+    let instances = [Vec4::new(0., 0., 0.5, 0.1)];
+    let kept = cull_instances(&view(), &GlobalTransform::IDENTITY, 1., &instances, |_| 1.);
+    assert!(kept.is_empty());
+}
+
+#[test]
+fn culls_in_world_space() {
+    let instances = [Vec4::new(0., 0., 5., 1.), Vec4::new(0., 0., -5., 1.)];
+    let transform = GlobalTransform::from_translation(Vec3::new(0., 0., -10.));
+    let kept = cull_instances(&view(), &transform, 0.5, &instances, |_| 1.);
+    assert_eq!(kept, vec![0, 1]);
+
+    let transform = GlobalTransform::from_rotation(Quat::from_rotation_y(std::f32::consts::PI));
+    let kept = cull_instances(&view(), &transform, 0.5, &instances, |_| 1.);
+    assert_eq!(kept, vec![0]);
+}
+
+#[test]
+fn thins_out_instances_from_the_end() {
+    let instances: Vec<Vec4> = (0..10)
+        .map(|i| Vec4::new(0., 0., -5. - i as f32, 1.))
+        .collect();
+    let kept = cull_instances(&view(), &GlobalTransform::IDENTITY, 0.5, &instances, |_| {
+        0.5
+    });
+    assert_eq!(kept, vec![0, 1, 2, 3, 4]);
+
+    // Instances further than 8 units away are thinned out to a fifth, which only the first two
+    // instances are in.
+    let kept = cull_instances(
+        &view(),
+        &GlobalTransform::IDENTITY,
+        0.5,
+        &instances,
+        |distance| if distance < 8. { 1. } else { 0.2 },
+    );
+    assert_eq!(kept, vec![0, 1, 2]);
+}
@@ -0,0 +1,56 @@
+use bevy::prelude::*;
+use frosty_grass::flatten::GrassFlattenMap;
+
+/// A map of one texel per unit over `[-4, 4]` on both axes.
+fn map() -> GrassFlattenMap {
+    let mut map = GrassFlattenMap::new(UVec2::splat(8), 0.5);
+    map.set_bounds(Rect::new(-4., -4., 4., 4.));
+    map
+}
+
+#[test]
+fn paints_nothing_until_bounds_are_known() {
+    let mut map = GrassFlattenMap::new(UVec2::splat(8), 0.5);
+    map.paint(Vec2::ZERO, 2., 1.);
+    assert!(map.values().iter().all(|value| *value == 0.));
+    assert_eq!(map.flatness(Vec2::ZERO), 0.);
+}
+
+#[test]
+fn paints_within_radius_fading_out() {
+    let mut map = map();
+    map.paint(Vec2::new(0.5, 0.5), 2., 1.);
+    assert_eq!(map.flatness(Vec2::new(0.5, 0.5)), 1.);
+    let edge = map.flatness(Vec2::new(1.5, 1.5));
+    assert!(edge > 0. && edge < 1., "{edge}");
+    assert_eq!(map.flatness(Vec2::new(3.5, 0.5)), 0.);
+    assert_eq!(map.flatness(Vec2::new(10., 0.5)), 0.);
+}
+
+#[test]
+fn painting_saturates_and_decays() {
+    let mut map = map();
+    map.paint(Vec2::new(0.5, 0.5), 1., 0.8);
+    map.paint(Vec2::new(0.5, 0.5), 1., 0.8);
+    assert_eq!(map.flatness(Vec2::new(0.5, 0.5)), 1.);
+    map.decay(0.25);
+    assert_eq!(map.flatness(Vec2::new(0.5, 0.5)), 0.75);
+    map.decay(1.);
+    assert!(map.values().iter().all(|value| *value == 0.));
+}
+
+#[test]
+fn moving_bounds_clears_the_map() {
+    let mut map = map();
+    map.paint(Vec2::new(0.5, 0.5), 1., 1.);
+    map.set_bounds(Rect::new(-4., -4., 4., 4.));
+    assert_eq!(map.flatness(Vec2::new(0.5, 0.5)), 1.);
+    map.set_bounds(Rect::new(-8., -8., 8., 8.));
+    assert!(map.values().iter().all(|value| *value == 0.));
+}
+
+#[test]
+#[should_panic(expected = "at least one texel")]
+fn rejects_empty_resolution() {
+    GrassFlattenMap::new(UVec2::new(16, 0), 0.5);
+}
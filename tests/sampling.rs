@@ -0,0 +1,342 @@
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::Indices,
+        render_resource::{Extent3d, PrimitiveTopology, TextureDimension, TextureFormat},
+    },
+};
+use frosty_grass::{
+    sampling::{
+        DensityMap, MeshSampler, PoissonDiskSampler, SlopeLimit, UniformRandomSampler, UpDirection,
+        VertexMask,
+    },
+    texture::{TextureChannel, TextureMap},
+};
+
+/// A flat `size` x `size` quad facing up, made of two triangles.
+fn quad_list(size: f32) -> Mesh {
+    Mesh::new(PrimitiveTopology::TriangleList)
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![
+                [0., 0., 0.],
+                [0., 0., size],
+                [size, 0., size],
+                [size, 0., 0.],
+            ],
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 1., 0.]; 4])
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_UV_0,
+            vec![[0., 0.], [0., 1.], [1., 1.], [1., 0.]],
+        )
+        .with_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])))
+}
+
+/// The same quad as [`quad_list`], as a triangle strip.
+fn quad_strip(size: f32) -> Mesh {
+    Mesh::new(PrimitiveTopology::TriangleStrip)
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![
+                [0., 0., size],
+                [size, 0., size],
+                [0., 0., 0.],
+                [size, 0., 0.],
+            ],
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 1., 0.]; 4])
+}
+
+fn in_quad(point: Vec3, size: f32) -> bool {
+    (0.0..=size).contains(&point.x) && (0.0..=size).contains(&point.z) && point.y.abs() < 1e-5
+}
+
+#[test]
+fn same_seed_gives_same_points() {
+    let mesh = quad_list(4.);
+    let sampler = UniformRandomSampler {
+        density: 8.,
+        seed: 42,
+        ..default()
+    };
+    let first = sampler.sample(&mesh);
+    let second = sampler.sample(&mesh);
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+}
+
+#[test]
+fn different_seeds_give_different_points() {
+    let mesh = quad_list(4.);
+    let a = UniformRandomSampler {
+        density: 8.,
+        seed: 1,
+        ..default()
+    }
+    .sample(&mesh);
+    let b = UniformRandomSampler {
+        density: 8.,
+        seed: 2,
+        ..default()
+    }
+    .sample(&mesh);
+    assert_eq!(a.len(), b.len());
+    assert_ne!(a, b);
+}
+
+#[test]
+fn strip_matches_list_density() {
+    let sampler = UniformRandomSampler {
+        density: 8.,
+        ..default()
+    };
+    let list = sampler.sample(&quad_list(4.));
+    let strip = sampler.sample(&quad_strip(4.));
+    assert_eq!(list.len(), strip.len());
+    assert!(strip.iter().all(|point| in_quad(*point, 4.)));
+}
+
+#[test]
+fn strip_restart_splits_strips() {
+    let sampler = UniformRandomSampler {
+        density: 8.,
+        ..default()
+    };
+    let mesh = quad_strip(4.).with_indices(Some(Indices::U16(vec![0, 1, 2, u16::MAX, 1, 2, 3])));
+    let points = sampler.sample(&mesh);
+    assert_eq!(points.len(), sampler.sample(&quad_list(4.)).len());
+    assert!(points.iter().all(|point| in_quad(*point, 4.)));
+}
+
+#[test]
+fn non_finite_triangles_are_skipped() {
+    let mut mesh = quad_list(4.);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vec![[0., 0., 0.], [0., 0., 4.], [4., 0., 4.], [f32::NAN, 0., 0.]],
+    );
+    let sampler = UniformRandomSampler {
+        density: 8.,
+        ..default()
+    };
+    let points = sampler.sample(&mesh);
+    assert!(!points.is_empty());
+    assert!(points.iter().all(|point| in_quad(*point, 4.)));
+
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[f32::NAN; 3]; 4]);
+    assert!(sampler.sample(&mesh).is_empty());
+}
+
+#[test]
+fn slope_limit_rejects_steep_triangles() {
+    let mut mesh = quad_list(4.);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[1., 0., 0.]; 4]);
+    let points = UniformRandomSampler {
+        density: 8.,
+        slope: SlopeLimit {
+            max_angle: 45.,
+            ..default()
+        },
+        ..default()
+    }
+    .sample(&mesh);
+    assert!(points.is_empty());
+}
+
+#[test]
+fn poisson_disk_keeps_min_distance() {
+    let sampler = PoissonDiskSampler {
+        density: 32.,
+        seed: 7,
+        min_distance: 0.2,
+        ..default()
+    };
+    let points = sampler.sample(&quad_list(4.));
+    assert!(!points.is_empty());
+    assert!(points.iter().all(|point| in_quad(*point, 4.)));
+    for (i, a) in points.iter().enumerate() {
+        for b in &points[i + 1..] {
+            assert!(a.distance(*b) >= 0.2);
+        }
+    }
+    assert_eq!(points, sampler.sample(&quad_list(4.)));
+}
+
+#[test]
+fn poisson_disk_spacing_crosses_triangles() {
+    // Two triangles sharing an edge along the quad's diagonal.
+    let sampler = PoissonDiskSampler {
+        density: 64.,
+        min_distance: 0.5,
+        ..default()
+    };
+    let points = sampler.sample(&quad_strip(2.));
+    for (i, a) in points.iter().enumerate() {
+        for b in &points[i + 1..] {
+            assert!(a.distance(*b) >= 0.5);
+        }
+    }
+}
+
+#[test]
+fn up_direction_selects_walls_and_ceilings() {
+    let mut wall = quad_list(4.);
+    wall.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[1., 0., 0.]; 4]);
+    let wall_sampler = UniformRandomSampler {
+        density: 8.,
+        slope: SlopeLimit {
+            max_angle: 10.,
+            up: UpDirection::Axis(Vec3::X),
+            ..default()
+        },
+        ..default()
+    };
+    assert!(!wall_sampler.sample(&wall).is_empty());
+
+    let overhang_sampler = UniformRandomSampler {
+        density: 8.,
+        slope: SlopeLimit {
+            min_angle: 170.,
+            max_angle: 180.,
+            ..default()
+        },
+        ..default()
+    };
+    assert!(overhang_sampler.sample(&quad_list(4.)).is_empty());
+    let mut ceiling = quad_list(4.);
+    ceiling.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., -1., 0.]; 4]);
+    assert!(!overhang_sampler.sample(&ceiling).is_empty());
+}
+
+#[test]
+fn radial_up_direction_follows_center() {
+    let mesh = quad_list(4.);
+    let sampler = |center: Vec3| UniformRandomSampler {
+        density: 8.,
+        slope: SlopeLimit {
+            max_angle: 30.,
+            up: UpDirection::Radial { center },
+            ..default()
+        },
+        ..default()
+    };
+    assert!(!sampler(Vec3::new(2., -100., 2.)).sample(&mesh).is_empty());
+    assert!(sampler(Vec3::new(2., 100., 2.)).sample(&mesh).is_empty());
+}
+
+#[test]
+fn up_directions_compare_normalized_axes() {
+    // A tilted grassable that is scaled keeps its up direction, up to rounding.
+    let up = UpDirection::Axis(Vec3::new(0.3, 1., 0.2));
+    let rotation = Mat4::from_rotation_x(0.7);
+    let scaled = up.transformed((rotation * Mat4::from_scale(Vec3::splat(1.37))).inverse());
+    let unscaled = up.transformed(rotation.inverse());
+    assert!(scaled.abs_diff_eq(&unscaled, 1e-4));
+
+    assert!(!up.abs_diff_eq(&UpDirection::Axis(Vec3::Y), 1e-4));
+    assert!(!up.abs_diff_eq(&UpDirection::Radial { center: Vec3::ZERO }, 1e-4));
+}
+
+#[test]
+fn samples_carry_interpolated_attributes() {
+    let samples = UniformRandomSampler {
+        density: 8.,
+        ..default()
+    }
+    .sample_attributes(&quad_list(4.));
+    assert!(!samples.is_empty());
+    for sample in samples {
+        assert!(
+            (sample.barycentric.x + sample.barycentric.y + sample.barycentric.z - 1.).abs() < 1e-5
+        );
+        assert!(sample.normal.abs_diff_eq(Vec3::Y, 1e-5));
+        // The quad's UVs map linearly from its XZ positions.
+        assert!(sample
+            .uv
+            .abs_diff_eq(Vec2::new(sample.position.x, sample.position.z) / 4., 1e-5));
+        assert!(sample.triangle < 2);
+    }
+}
+
+/// An 8x1 image whose left half is black and right half is white.
+fn half_white_image() -> Image {
+    let data = (0..8)
+        .flat_map(|x| if x < 4 { [0, 0, 0, 255] } else { [255; 4] })
+        .collect();
+    Image::new(
+        Extent3d {
+            width: 8,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8Unorm,
+    )
+}
+
+#[test]
+fn density_map_clears_dark_areas() {
+    let density_map = DensityMap {
+        texture: TextureMap::from_image(&half_white_image()).unwrap(),
+        channel: TextureChannel::R,
+    };
+    let uniform = UniformRandomSampler {
+        density: 16.,
+        density_map: Some(density_map.clone()),
+        ..default()
+    }
+    .sample_attributes(&quad_list(4.));
+    let poisson = PoissonDiskSampler {
+        density: 16.,
+        density_map: Some(density_map),
+        min_distance: 0.05,
+        ..default()
+    }
+    .sample_attributes(&quad_list(4.));
+    for samples in [uniform, poisson] {
+        // Texels wrap around, so the left edge still blends with the white right edge.
+        assert!(samples
+            .iter()
+            .all(|sample| !(0.07..0.43).contains(&sample.uv.x)));
+        assert!(samples.iter().any(|sample| sample.uv.x > 0.6));
+    }
+}
+
+#[test]
+fn vertex_mask_rejects_unpainted_areas() {
+    // The mask rises linearly from 0 at x = 0 to 1 at x = 4.
+    let mesh = quad_list(4.).with_inserted_attribute(
+        Mesh::ATTRIBUTE_COLOR,
+        vec![
+            [0., 0., 0., 1.],
+            [0., 0., 0., 1.],
+            [1., 1., 1., 1.],
+            [1., 1., 1., 1.],
+        ],
+    );
+    let sampler = UniformRandomSampler {
+        density: 8.,
+        vertex_mask: Some(VertexMask {
+            attribute: Mesh::ATTRIBUTE_COLOR.id,
+            channel: 0,
+            cutoff: 0.5,
+        }),
+        ..default()
+    };
+    let points = sampler.sample(&mesh);
+    assert!(!points.is_empty());
+    assert!(points.iter().all(|point| point.x >= 2. - 1e-4));
+    assert!(
+        points.len()
+            < UniformRandomSampler {
+                density: 8.,
+                ..default()
+            }
+            .sample(&mesh)
+            .len()
+    );
+
+    assert!(sampler.sample(&quad_list(4.)).is_empty());
+}
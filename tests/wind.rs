@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use frosty_grass::wind::{
+    max_zone_sway, tiling_noise, GrassWindZone, WindZoneKind, MAX_WIND_ZONES,
+};
+
+const SIZE: usize = 64;
+
+/// The largest difference between horizontally neighbouring values, and between the values on
+/// the right edge and those they wrap around to on the left edge.
+fn steps(noise: &[f32]) -> (f32, f32) {
+    let mut inner = 0_f32;
+    let mut wrap = 0_f32;
+    for row in noise.chunks(SIZE) {
+        for pair in row.windows(2) {
+            inner = inner.max((pair[1] - pair[0]).abs());
+        }
+        wrap = wrap.max((row[0] - row[SIZE - 1]).abs());
+    }
+    (inner, wrap)
+}
+
+#[test]
+fn noise_stays_within_unit_range() {
+    let noise = tiling_noise(SIZE as u32, 4, 2, 7);
+    assert_eq!(noise.len(), SIZE * SIZE);
+    assert!(noise.iter().all(|value| (0. ..=1.).contains(value)));
+}
+
+#[test]
+fn noise_tiles_without_seams() {
+    let noise = tiling_noise(SIZE as u32, 4, 2, 7);
+    let (inner, wrap) = steps(&noise);
+    assert!(wrap <= inner, "seam of {wrap} against steps of {inner}");
+
+    // Transposed, to check the seam between the top and bottom rows.
+    let transposed: Vec<f32> = (0..SIZE * SIZE)
+        .map(|i| noise[(i % SIZE) * SIZE + i / SIZE])
+        .collect();
+    let (inner, wrap) = steps(&transposed);
+    assert!(wrap <= inner, "seam of {wrap} against steps of {inner}");
+}
+
+#[test]
+fn noise_is_seeded() {
+    assert_eq!(tiling_noise(16, 2, 1, 3), tiling_noise(16, 2, 1, 3));
+    assert_ne!(tiling_noise(16, 2, 1, 3), tiling_noise(16, 2, 1, 4));
+}
+
+#[test]
+fn zones_push_blades_within_their_radius() {
+    let zone = GrassWindZone {
+        radius: 4.,
+        strength: 2.,
+        falloff: 1.,
+        ..default()
+    };
+    let transform = GlobalTransform::from_translation(Vec3::new(1., 0., 1.));
+    let push = zone.push(&transform, Vec3::new(3., 0., 1.));
+    assert!((push - Vec2::new(1., 0.)).length() < 1e-5);
+    assert_eq!(zone.push(&transform, Vec3::new(6., 0., 1.)), Vec2::ZERO);
+}
+
+#[test]
+fn zone_kinds_push_in_their_directions() {
+    let at = |kind| GrassWindZone {
+        kind,
+        falloff: 0.,
+        ..default()
+    };
+    // Facing +X.
+    let transform = GlobalTransform::from(Transform::default().looking_to(Vec3::X, Vec3::Y));
+    let position = Vec3::new(0., 0., -2.);
+    let push = |kind| at(kind).push(&transform, position);
+    assert!((push(WindZoneKind::Radial) - Vec2::new(0., -1.)).length() < 1e-5);
+    assert!((push(WindZoneKind::Directional) - Vec2::new(1., 0.)).length() < 1e-5);
+    // Counterclockwise seen from above, from -Z towards -X.
+    assert!((push(WindZoneKind::Vortex) - Vec2::new(-1., 0.)).length() < 1e-5);
+}
+
+#[test]
+fn zone_sway_adds_up_the_strongest_zones() {
+    let zone = |strength| GrassWindZone {
+        strength,
+        ..default()
+    };
+    assert_eq!(max_zone_sway(&[zone(1.), zone(-2.)]), 3.);
+    let zones: Vec<_> = (0..MAX_WIND_ZONES + 4).map(|_| zone(0.5)).collect();
+    assert_eq!(max_zone_sway(&zones), MAX_WIND_ZONES as f32 * 0.5);
+}